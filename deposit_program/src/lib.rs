@@ -5,45 +5,97 @@ use thiserror::Error;
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
     declare_id,
-    entrypoint,
     entrypoint::ProgramResult,
     msg,
     program::invoke,
     program_error::ProgramError,
     pubkey::Pubkey,
-    sysvar::{rent::Rent, Sysvar},
+    sysvar::{clock::Clock, rent::Rent, Sysvar},
 };
 
+// Debug-only logging, shared with the programs in `src/`
+#[macro_use]
+#[path = "../../src/logging.rs"]
+mod logging;
+
+// Per-user audit log, shared with the programs in `src/`
+#[path = "../../src/audit_log.rs"]
+pub mod audit_log;
+
+// Admin-managed address blocklist, shared with the programs in `src/`
+#[path = "../../src/blocklist.rs"]
+mod blocklist;
+
+// CPI caller checks, shared with the programs in `src/`
+#[path = "../../src/cpi_guard.rs"]
+mod cpi_guard;
+
+// Unit test helpers, shared with the programs in `src/`
+#[cfg(test)]
+#[path = "../../src/test_utils.rs"]
+mod test_utils;
+
 // Define the program ID (Replace with your actual program ID)
 declare_id!("CkqWjTWzRMAtYN3CSs8Gp4K9H891htmaN1ysNXqcULc8");
 
+/// Authority allowed to block and unblock addresses.
+/// TODO: replace before deployment. The system program ID is a valid
+/// placeholder no one can sign for, so blocklist management stays disabled.
+pub const ADMIN: Pubkey = solana_program::system_program::ID;
+
+/// Programs allowed to withdraw via CPI (see Configuration in the README)
+pub const CPI_ALLOWLIST: Option<&[Pubkey]> = None;
+
 // Error definitions
 #[derive(Error, Debug, Copy, Clone)]
 pub enum DepositError {
     /// Invalid instruction
-    #[error("Invalid Instruction")]
+    #[error("Invalid instruction")]
     InvalidInstruction,
 
     /// Not rent exempt
-    #[error("Not Rent Exempt")]
+    #[error("Not rent exempt")]
     NotRentExempt,
 
     /// Insufficient funds
-    #[error("Insufficient Funds")]
+    #[error("Insufficient funds")]
     InsufficientFunds,
 
     /// Amount overflow
-    #[error("Amount Overflow")]
+    #[error("Arithmetic overflow")]
     AmountOverflow,
 
     /// Unauthorized access
-    #[error("Unauthorized Access")]
+    #[error("Unauthorized access")]
     Unauthorized,
+
+    /// Zero deposit or withdrawal amount
+    #[error("Expected amount to be greater than zero")]
+    AmountMustBeGreaterThanZero,
+
+    /// Expected nonce does not match the user account
+    #[error("Nonce does not match the account")]
+    NonceMismatch,
+
+    /// Address is on the admin blocklist
+    #[error("Address is blocked")]
+    AddressBlocked,
+
+    /// Withdraw reached via CPI from a program outside `CPI_ALLOWLIST`
+    #[error("Calling program is not allowed to invoke this instruction")]
+    UnauthorizedCpiCaller,
+
+    /// User account in the legacy layout, to be grown with `MigrateAccount`
+    #[error("User account uses the legacy layout and must be migrated")]
+    AccountNeedsMigration,
 }
 
+/// First `DepositError` code (see Error Codes in the README)
+pub const ERROR_CODE_BASE: u32 = 4000;
+
 impl From<DepositError> for ProgramError {
     fn from(e: DepositError) -> Self {
-        ProgramError::Custom(e as u32)
+        ProgramError::Custom(ERROR_CODE_BASE + e as u32)
     }
 }
 
@@ -53,14 +105,38 @@ pub enum DepositInstruction {
     /// Initializes a new user account
     InitializeAccount,
 
-    /// Deposits lamports into the user account
-    Deposit { amount: u64 },
+    /// Deposits lamports into the user account. If `expected_nonce` is
+    /// set, it must equal the account's current nonce.
+    Deposit { amount: u64, expected_nonce: Option<u64> },
+
+    /// Withdraws lamports from the user account. An amount of
+    /// `WITHDRAW_ALL` withdraws the whole balance at execution time. If
+    /// `expected_nonce` is set, it must equal the account's current nonce.
+    Withdraw { amount: u64, expected_nonce: Option<u64> },
+
+    /// Blocks `address` from withdrawing. Admin only.
+    BlockAddress { address: Pubkey },
 
-    /// Withdraws lamports from the user account
-    Withdraw { amount: u64 },
+    /// Lifts a block on `address`. Admin only.
+    UnblockAddress { address: Pubkey },
+
+    /// Creates the signer's audit log. Deposits and withdrawals record
+    /// themselves in it when it is passed as their last account.
+    InitializeAuditLog,
+
+    /// Grows a user account created before `nonce` was added to the
+    /// current layout, starting its nonce at 0. The owner pays the rent for
+    /// the added bytes.
+    MigrateAccount,
 }
 
 // Account data structure
+//
+// Fixed-width Borsh layout with the owner first. Fields may only be appended
+// so the offsets below stay valid for getProgramAccounts memcmp filters.
+// Accounts created before `nonce` was added are `LEGACY_USER_ACCOUNT_LEN`
+// bytes long; they have to be grown with MigrateAccount before they can be
+// used again.
 #[derive(BorshSerialize, BorshDeserialize, Debug)]
 pub struct UserAccount {
     /// The owner of the account
@@ -68,10 +144,40 @@ pub struct UserAccount {
 
     /// The balance of lamports in the account
     pub balance: u64,
+
+    /// Number of deposits and withdrawals applied to the account. Relayers
+    /// pass it as `expected_nonce` so an intent executes at most once.
+    pub nonce: u64,
 }
 
+/// Byte offset of `UserAccount::owner`
+pub const USER_ACCOUNT_OWNER_OFFSET: usize = 0;
+
+/// Byte offset of `UserAccount::balance`
+pub const USER_ACCOUNT_BALANCE_OFFSET: usize = 32;
+
+/// Byte offset of `UserAccount::nonce`
+pub const USER_ACCOUNT_NONCE_OFFSET: usize = 40;
+
+/// Serialized size of `UserAccount`
+pub const USER_ACCOUNT_LEN: usize = 48;
+
+/// Serialized size of `UserAccount` before `nonce` was added
+pub const LEGACY_USER_ACCOUNT_LEN: usize = 40;
+
+// Account data structure before `nonce` was added
+#[derive(BorshDeserialize)]
+struct LegacyUserAccount {
+    owner: Pubkey,
+    balance: u64,
+}
+
+/// Withdraw amount meaning "the whole balance at execution time"
+pub const WITHDRAW_ALL: u64 = u64::MAX;
+
 // Program entrypoint
-entrypoint!(process_instruction);
+#[cfg(not(feature = "no-entrypoint"))]
+solana_program::entrypoint!(process_instruction);
 
 // Program entrypoint's implementation
 pub fn process_instruction(
@@ -87,11 +193,23 @@ pub fn process_instruction(
         DepositInstruction::InitializeAccount => {
             initialize_account(program_id, accounts)
         }
-        DepositInstruction::Deposit { amount } => {
-            deposit(program_id, accounts, amount)
+        DepositInstruction::Deposit { amount, expected_nonce } => {
+            deposit(program_id, accounts, amount, expected_nonce)
+        }
+        DepositInstruction::Withdraw { amount, expected_nonce } => {
+            withdraw(program_id, accounts, amount, expected_nonce)
+        }
+        DepositInstruction::BlockAddress { address } => {
+            block_address(program_id, accounts, address)
+        }
+        DepositInstruction::UnblockAddress { address } => {
+            unblock_address(program_id, accounts, address)
+        }
+        DepositInstruction::InitializeAuditLog => {
+            initialize_audit_log(program_id, accounts)
         }
-        DepositInstruction::Withdraw { amount } => {
-            withdraw(program_id, accounts, amount)
+        DepositInstruction::MigrateAccount => {
+            migrate_account(program_id, accounts)
         }
     }
 }
@@ -141,12 +259,13 @@ fn initialize_account(
     let user_account_data = UserAccount {
         owner: *user.key,
         balance: 0,
+        nonce: 0,
     };
 
     // Serialize the user account data into the account's data field
     user_account_data.serialize(&mut &mut user_account.data.borrow_mut()[..])?;
 
-    msg!("User account initialized for {}", user.key);
+    debug_msg!("User account initialized for {}", user.key);
 
     Ok(())
 }
@@ -156,6 +275,7 @@ fn deposit(
     program_id: &Pubkey,
     accounts: &[AccountInfo],
     amount: u64,
+    expected_nonce: Option<u64>,
 ) -> ProgramResult {
     let account_info_iter = &mut accounts.iter();
 
@@ -164,6 +284,11 @@ fn deposit(
     let user_account = next_account_info(account_info_iter)?;
     let system_program = next_account_info(account_info_iter)?;
 
+    // Reject zero amounts so no-op transactions fail loudly
+    if amount == 0 {
+        return Err(DepositError::AmountMustBeGreaterThanZero.into());
+    }
+
     // Check that the user signed the transaction
     if !user.is_signer {
         return Err(ProgramError::MissingRequiredSignature);
@@ -175,14 +300,16 @@ fn deposit(
     }
 
     // Deserialize UserAccount data
-    let mut user_account_data =
-        UserAccount::try_from_slice(&user_account.data.borrow())?;
+    let mut user_account_data = load_user_account(user_account)?;
 
     // Verify the account owner
     if user_account_data.owner != *user.key {
         return Err(DepositError::Unauthorized.into());
     }
 
+    // Check and advance the nonce
+    use_nonce(&mut user_account_data, expected_nonce)?;
+
     // Transfer lamports from user to user_account
     invoke(
         &solana_program::system_instruction::transfer(
@@ -204,7 +331,17 @@ fn deposit(
     // Serialize the updated data back into the account
     user_account_data.serialize(&mut &mut user_account.data.borrow_mut()[..])?;
 
-    msg!(
+    // The user's audit log is an optional trailing account
+    if let Some(audit_log) = account_info_iter.next() {
+        let entry = audit_log::AuditEntry {
+            action: audit_log::AuditAction::Deposit,
+            amount,
+            timestamp: Clock::get()?.unix_timestamp,
+        };
+        audit_log::record(program_id, user.key, audit_log, &entry)?;
+    }
+
+    debug_msg!(
         "{} deposited {} lamports",
         user.key,
         amount
@@ -213,11 +350,116 @@ fn deposit(
     Ok(())
 }
 
+/// Deserializes a user account, failing with AccountNeedsMigration for one
+/// in the legacy layout, whose nonce could not be stored
+fn load_user_account(user_account: &AccountInfo) -> Result<UserAccount, ProgramError> {
+    if user_account.data_len() == LEGACY_USER_ACCOUNT_LEN {
+        msg!(
+            "User account is {} bytes, migrate it to {} bytes first",
+            LEGACY_USER_ACCOUNT_LEN,
+            USER_ACCOUNT_LEN
+        );
+        return Err(DepositError::AccountNeedsMigration.into());
+    }
+
+    let user_account_data = UserAccount::try_from_slice(&user_account.data.borrow())?;
+    Ok(user_account_data)
+}
+
+/// Handles MigrateAccount instruction
+fn migrate_account(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+
+    // Get accounts
+    let user = next_account_info(account_info_iter)?;
+    let user_account = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+    let rent_sysvar = next_account_info(account_info_iter)?;
+    let rent = &Rent::from_account_info(rent_sysvar)?;
+
+    // Check that the user signed the transaction
+    if !user.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    // Check that the user_account is owned by the program
+    if user_account.owner != program_id {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    // Only legacy accounts need migrating
+    if user_account.data_len() != LEGACY_USER_ACCOUNT_LEN {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    // Verify the account owner
+    let legacy = LegacyUserAccount::try_from_slice(&user_account.data.borrow())?;
+    if legacy.owner != *user.key {
+        return Err(DepositError::Unauthorized.into());
+    }
+
+    let extra_rent = rent.minimum_balance(USER_ACCOUNT_LEN)
+        .saturating_sub(rent.minimum_balance(LEGACY_USER_ACCOUNT_LEN));
+    invoke(
+        &solana_program::system_instruction::transfer(
+            user.key,
+            user_account.key,
+            extra_rent,
+        ),
+        &[
+            user.clone(),
+            user_account.clone(),
+            system_program.clone(),
+        ],
+    )?;
+
+    user_account.realloc(USER_ACCOUNT_LEN, false)?;
+
+    // Write the account back in the current layout
+    let user_account_data = UserAccount {
+        owner: legacy.owner,
+        balance: legacy.balance,
+        nonce: 0,
+    };
+    user_account_data.serialize(&mut &mut user_account.data.borrow_mut()[..])?;
+
+    debug_msg!("User account of {} migrated", user.key);
+
+    Ok(())
+}
+
+/// Fails unless `expected_nonce`, if given, equals the account's nonce, then
+/// advances the nonce
+fn use_nonce(
+    user_account_data: &mut UserAccount,
+    expected_nonce: Option<u64>,
+) -> ProgramResult {
+    if let Some(expected_nonce) = expected_nonce {
+        if expected_nonce != user_account_data.nonce {
+            msg!(
+                "Nonce mismatch: expected {}, account is at {}",
+                expected_nonce,
+                user_account_data.nonce
+            );
+            return Err(DepositError::NonceMismatch.into());
+        }
+    }
+
+    user_account_data.nonce = user_account_data.nonce.checked_add(1)
+        .ok_or(DepositError::AmountOverflow)?;
+
+    Ok(())
+}
+
 /// Handles Withdraw instruction
 fn withdraw(
     program_id: &Pubkey,
     accounts: &[AccountInfo],
     amount: u64,
+    expected_nonce: Option<u64>,
 ) -> ProgramResult {
     let account_info_iter = &mut accounts.iter();
 
@@ -225,36 +467,88 @@ fn withdraw(
     let user = next_account_info(account_info_iter)?;
     let user_account = next_account_info(account_info_iter)?;
     let _system_program = next_account_info(account_info_iter)?;
+    let rent_sysvar = next_account_info(account_info_iter)?;
+    let rent = &Rent::from_account_info(rent_sysvar)?;
+    let blocklist_marker = next_account_info(account_info_iter)?;
+
+    // Restrict which programs may withdraw via CPI
+    if let Some(allowlist) = CPI_ALLOWLIST {
+        let instructions_sysvar = next_account_info(account_info_iter)?;
+        if !cpi_guard::is_allowed_caller(program_id, instructions_sysvar, allowlist)? {
+            return Err(DepositError::UnauthorizedCpiCaller.into());
+        }
+    }
+
+    // Reject zero amounts so no-op transactions fail loudly
+    if amount == 0 {
+        return Err(DepositError::AmountMustBeGreaterThanZero.into());
+    }
 
     // Check that the user signed the transaction
     if !user.is_signer {
         return Err(ProgramError::MissingRequiredSignature);
     }
 
+    // Blocked addresses cannot withdraw
+    if blocklist::is_blocked(program_id, user.key, blocklist_marker)? {
+        return Err(DepositError::AddressBlocked.into());
+    }
+
     // Check that the user_account is owned by the program
     if user_account.owner != program_id {
         return Err(ProgramError::IncorrectProgramId);
     }
 
     // Deserialize UserAccount data
-    let mut user_account_data =
-        UserAccount::try_from_slice(&user_account.data.borrow())?;
+    let mut user_account_data = load_user_account(user_account)?;
 
     // Verify the account owner
     if user_account_data.owner != *user.key {
         return Err(DepositError::Unauthorized.into());
     }
 
+    // Check and advance the nonce
+    use_nonce(&mut user_account_data, expected_nonce)?;
+
+    // Resolve a withdraw-all request against the current balance
+    let amount = if amount == WITHDRAW_ALL {
+        user_account_data.balance
+    } else {
+        amount
+    };
+
+    // Withdrawing everything from an empty account would be a silent no-op
+    if amount == 0 {
+        return Err(DepositError::AmountMustBeGreaterThanZero.into());
+    }
+
     // Check if the user has sufficient balance
     if user_account_data.balance < amount {
+        msg!(
+            "Insufficient funds: requested {} lamports, balance {} lamports",
+            amount,
+            user_account_data.balance
+        );
         return Err(DepositError::InsufficientFunds.into());
     }
 
-    // Transfer lamports from user_account back to user
-    **user_account.try_borrow_mut_lamports()? = user_account
+    // Check that the account stays rent exempt after the withdrawal
+    let remaining = user_account
         .lamports()
         .checked_sub(amount)
         .ok_or(DepositError::AmountOverflow)?;
+    let minimum_balance = rent.minimum_balance(user_account.data_len());
+    if remaining < minimum_balance {
+        msg!(
+            "Withdrawal would leave {} lamports, rent exemption needs {} lamports",
+            remaining,
+            minimum_balance
+        );
+        return Err(DepositError::NotRentExempt.into());
+    }
+
+    // Transfer lamports from user_account back to user
+    **user_account.try_borrow_mut_lamports()? = remaining;
 
     **user.try_borrow_mut_lamports()? = user
         .lamports()
@@ -268,7 +562,17 @@ fn withdraw(
     // Serialize the updated data back into the account
     user_account_data.serialize(&mut &mut user_account.data.borrow_mut()[..])?;
 
-    msg!(
+    // The user's audit log is an optional trailing account
+    if let Some(audit_log) = account_info_iter.next() {
+        let entry = audit_log::AuditEntry {
+            action: audit_log::AuditAction::Withdraw,
+            amount,
+            timestamp: Clock::get()?.unix_timestamp,
+        };
+        audit_log::record(program_id, user.key, audit_log, &entry)?;
+    }
+
+    debug_msg!(
         "{} withdrew {} lamports",
         user.key,
         amount
@@ -276,3 +580,178 @@ fn withdraw(
 
     Ok(())
 }
+
+/// Handles BlockAddress instruction
+fn block_address(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    address: Pubkey,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+
+    // Get accounts
+    let admin = next_account_info(account_info_iter)?;
+    let blocklist_marker = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+    let rent_sysvar = next_account_info(account_info_iter)?;
+    let rent = &Rent::from_account_info(rent_sysvar)?;
+
+    // Check that the admin signed the transaction
+    if !admin.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if *admin.key != ADMIN {
+        return Err(DepositError::Unauthorized.into());
+    }
+
+    blocklist::block(program_id, &address, admin, blocklist_marker, system_program, rent)?;
+
+    msg!("Address blocked: {}", address);
+
+    Ok(())
+}
+
+/// Handles UnblockAddress instruction
+fn unblock_address(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    address: Pubkey,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+
+    // Get accounts
+    let admin = next_account_info(account_info_iter)?;
+    let blocklist_marker = next_account_info(account_info_iter)?;
+
+    // Check that the admin signed the transaction
+    if !admin.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if *admin.key != ADMIN {
+        return Err(DepositError::Unauthorized.into());
+    }
+
+    blocklist::unblock(program_id, &address, admin, blocklist_marker)?;
+
+    msg!("Address unblocked: {}", address);
+
+    Ok(())
+}
+
+/// Handles InitializeAuditLog instruction
+fn initialize_audit_log(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+
+    // Get accounts
+    let user = next_account_info(account_info_iter)?;
+    let audit_log = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+    let rent_sysvar = next_account_info(account_info_iter)?;
+    let rent = &Rent::from_account_info(rent_sysvar)?;
+
+    // Check that the user signed the transaction
+    if !user.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    audit_log::create(program_id, user, audit_log, system_program, rent)?;
+
+    debug_msg!("Audit log initialized for {}", user.key);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::test_utils::{rent_sysvar_data, serialize_input, test_account};
+    use solana_program::{entrypoint, system_program};
+
+    // User account data as written before `nonce` was added
+    fn legacy_user_account_data(owner: &Pubkey, balance: u64) -> Vec<u8> {
+        let mut data = owner.to_bytes().to_vec();
+        data.extend_from_slice(&balance.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn test_deposit_rejects_legacy_account() {
+        let program_id = Pubkey::new_unique();
+        let user_key = Pubkey::new_unique();
+        let rent = Rent::default();
+
+        let mut input = serialize_input(
+            &program_id,
+            &[
+                test_account(user_key, true, 1_000_000_000, vec![], system_program::id()),
+                test_account(
+                    Pubkey::new_unique(),
+                    false,
+                    rent.minimum_balance(LEGACY_USER_ACCOUNT_LEN) + 500_000,
+                    legacy_user_account_data(&user_key, 500_000),
+                    program_id,
+                ),
+                test_account(system_program::id(), false, 0, vec![], system_program::id()),
+            ],
+            &DepositInstruction::Deposit { amount: 100, expected_nonce: None }.try_to_vec().unwrap(),
+        );
+        let (program_id, accounts, instruction_data) =
+            unsafe { entrypoint::deserialize(input.as_mut_ptr() as *mut u8) };
+
+        let result = process_instruction(program_id, &accounts, instruction_data);
+        assert_eq!(result, Err(DepositError::AccountNeedsMigration.into()));
+        assert_eq!(accounts[1].data_len(), LEGACY_USER_ACCOUNT_LEN);
+    }
+
+    #[test]
+    fn test_migrate_account() {
+        let program_id = Pubkey::new_unique();
+        let user_key = Pubkey::new_unique();
+        let rent = Rent::default();
+
+        // CPIs do not run outside the runtime, so the rent top-up is not observed
+        let mut input = serialize_input(
+            &program_id,
+            &[
+                test_account(user_key, true, 1_000_000_000, vec![], system_program::id()),
+                test_account(
+                    Pubkey::new_unique(),
+                    false,
+                    rent.minimum_balance(LEGACY_USER_ACCOUNT_LEN) + 500_000,
+                    legacy_user_account_data(&user_key, 500_000),
+                    program_id,
+                ),
+                test_account(system_program::id(), false, 0, vec![], system_program::id()),
+                test_account(solana_program::sysvar::rent::id(), false, 0, rent_sysvar_data(&rent), system_program::id()),
+            ],
+            &DepositInstruction::MigrateAccount.try_to_vec().unwrap(),
+        );
+        let (program_id, accounts, instruction_data) =
+            unsafe { entrypoint::deserialize(input.as_mut_ptr() as *mut u8) };
+
+        process_instruction(program_id, &accounts, instruction_data).unwrap();
+
+        let user_account_data = UserAccount::try_from_slice(&accounts[1].data.borrow()).unwrap();
+        assert_eq!(accounts[1].data_len(), USER_ACCOUNT_LEN);
+        assert_eq!(user_account_data.owner, user_key);
+        assert_eq!(user_account_data.balance, 500_000);
+        assert_eq!(user_account_data.nonce, 0);
+
+        // A migrated account cannot be migrated again
+        let result = process_instruction(program_id, &accounts, instruction_data);
+        assert_eq!(result, Err(ProgramError::InvalidAccountData));
+
+        // Deposits work again, starting from nonce 0
+        let deposit = DepositInstruction::Deposit { amount: 100, expected_nonce: Some(0) }.try_to_vec().unwrap();
+        process_instruction(program_id, &accounts[..3], &deposit).unwrap();
+
+        let user_account_data = UserAccount::try_from_slice(&accounts[1].data.borrow()).unwrap();
+        assert_eq!(user_account_data.balance, 500_100);
+        assert_eq!(user_account_data.nonce, 1);
+    }
+}
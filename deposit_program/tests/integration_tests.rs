@@ -9,8 +9,8 @@ use deposit_program::instruction::DepositInstruction; // Adjust this import base
 
 #[derive(BorshSerialize, BorshDeserialize)] // Ensure this is added to your struct definition
 pub enum DepositInstruction {
-    Deposit { amount: u64 },
-    Withdraw { amount: u64 },
+    Deposit { amount: u64, expected_nonce: Option<u64> },
+    Withdraw { amount: u64, expected_nonce: Option<u64> },
 }
 
 #[tokio::test]
@@ -29,7 +29,7 @@ async fn test_deposit() {
     let deposit_amount = 100;
     let deposit_instruction = Instruction::new_with_borsh(
         program_id,
-        &DepositInstruction::Deposit { amount: deposit_amount },
+        &DepositInstruction::Deposit { amount: deposit_amount, expected_nonce: None },
         vec![user.pubkey()],
     );
 
@@ -62,7 +62,7 @@ async fn test_withdraw() {
     let withdraw_amount = 50;
     let withdraw_instruction = Instruction::new_with_borsh(
         program_id,
-        &DepositInstruction::Withdraw { amount: withdraw_amount },
+        &DepositInstruction::Withdraw { amount: withdraw_amount, expected_nonce: None },
         vec![user.pubkey()],
     );
 
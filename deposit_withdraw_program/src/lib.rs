@@ -0,0 +1,7 @@
+// Standalone build of the deposit/withdraw program in `src/`, deployed as
+// `deposit_withdraw_program.so`. The modules it declares (logging, audit_log,
+// blocklist, cpi_guard, ed25519) are resolved next to it in `src/`.
+#[path = "../../src/main_deposit_withdraw.rs"]
+mod program;
+
+pub use program::*;
@@ -0,0 +1,122 @@
+// Optional per-user audit log (seeds [user, "audit_log"]): a u64 count of
+// actions ever recorded followed by a ring buffer of AUDIT_LOG_CAPACITY
+// entries. Entry `count % AUDIT_LOG_CAPACITY` is the next one overwritten.
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::{
+    account_info::AccountInfo,
+    entrypoint::ProgramResult,
+    program::invoke_signed,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    rent::Rent,
+    system_instruction,
+};
+
+// Number of recent actions kept in a user's audit log
+pub const AUDIT_LOG_CAPACITY: usize = 32;
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq)]
+pub enum AuditAction {
+    Deposit,
+    Withdraw,
+    RelayedWithdraw,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq)]
+pub struct AuditEntry {
+    pub action: AuditAction,
+    pub amount: u64,
+    pub timestamp: i64,
+}
+
+pub const AUDIT_ENTRY_LEN: usize = 17;
+pub const AUDIT_LOG_HEADER_LEN: usize = 8;
+pub const AUDIT_LOG_LEN: usize = AUDIT_LOG_HEADER_LEN + AUDIT_LOG_CAPACITY * AUDIT_ENTRY_LEN;
+
+pub fn log_address(program_id: &Pubkey, user: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[user.as_ref(), b"audit_log"], program_id)
+}
+
+// Creates `user`'s audit log, funded by `user`. A fresh account is zeroed,
+// which is an empty log.
+pub fn create<'a>(
+    program_id: &Pubkey,
+    user: &AccountInfo<'a>,
+    audit_log: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    rent: &Rent,
+) -> ProgramResult {
+    let (pda, bump_seed) = log_address(program_id, user.key);
+    if pda != *audit_log.key {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    invoke_signed(
+        &system_instruction::create_account(
+            user.key,
+            audit_log.key,
+            rent.minimum_balance(AUDIT_LOG_LEN),
+            AUDIT_LOG_LEN as u64,
+            program_id,
+        ),
+        &[user.clone(), audit_log.clone(), system_program.clone()],
+        &[&[user.key.as_ref(), b"audit_log", &[bump_seed]]],
+    )
+}
+
+// Records `entry` in `user`'s audit log
+pub fn record(program_id: &Pubkey, user: &Pubkey, audit_log: &AccountInfo, entry: &AuditEntry) -> ProgramResult {
+    let (pda, _) = log_address(program_id, user);
+    if pda != *audit_log.key || audit_log.owner != program_id || audit_log.data_len() != AUDIT_LOG_LEN {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    push(&mut audit_log.data.borrow_mut(), entry)
+}
+
+// Writes `entry` over the oldest slot of an audit log and bumps its count
+fn push(data: &mut [u8], entry: &AuditEntry) -> ProgramResult {
+    let mut count_bytes = [0; 8];
+    count_bytes.copy_from_slice(&data[..AUDIT_LOG_HEADER_LEN]);
+    let count = u64::from_le_bytes(count_bytes);
+
+    let start = AUDIT_LOG_HEADER_LEN + (count % AUDIT_LOG_CAPACITY as u64) as usize * AUDIT_ENTRY_LEN;
+    entry.serialize(&mut &mut data[start..start + AUDIT_ENTRY_LEN])?;
+
+    let count = count.checked_add(1).ok_or(ProgramError::ArithmeticOverflow)?;
+    data[..AUDIT_LOG_HEADER_LEN].copy_from_slice(&count.to_le_bytes());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_audit_entry_len() {
+        let entry = AuditEntry { action: AuditAction::Withdraw, amount: 500_000, timestamp: 1_700_000_000 };
+        assert_eq!(entry.try_to_vec().unwrap().len(), AUDIT_ENTRY_LEN);
+    }
+
+    #[test]
+    fn test_push_wraps_around() {
+        let mut data = vec![0; AUDIT_LOG_LEN];
+        let entry = |amount| AuditEntry { action: AuditAction::Deposit, amount, timestamp: 1_700_000_000 };
+        let slot = |data: &[u8], i: usize| {
+            let start = AUDIT_LOG_HEADER_LEN + i * AUDIT_ENTRY_LEN;
+            AuditEntry::try_from_slice(&data[start..start + AUDIT_ENTRY_LEN]).unwrap()
+        };
+
+        for amount in 1..=AUDIT_LOG_CAPACITY as u64 {
+            push(&mut data, &entry(amount)).unwrap();
+        }
+        assert_eq!(slot(&data, 0), entry(1));
+        assert_eq!(slot(&data, AUDIT_LOG_CAPACITY - 1), entry(AUDIT_LOG_CAPACITY as u64));
+
+        // Once full, the oldest entry is overwritten
+        push(&mut data, &entry(100)).unwrap();
+        assert_eq!(slot(&data, 0), entry(100));
+        assert_eq!(slot(&data, 1), entry(2));
+        assert_eq!(&data[..AUDIT_LOG_HEADER_LEN], &(AUDIT_LOG_CAPACITY as u64 + 1).to_le_bytes());
+    }
+}
@@ -0,0 +1,102 @@
+// Admin-managed address blocklist. An address is blocked while the program
+// owns a marker PDA at seeds ["blocked", address].
+use solana_program::{
+    account_info::AccountInfo,
+    entrypoint::ProgramResult,
+    program::invoke_signed,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    rent::Rent,
+    system_instruction,
+    system_program,
+};
+
+pub fn marker_address(program_id: &Pubkey, address: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"blocked", address.as_ref()], program_id)
+}
+
+// Returns whether `address` is blocked. `marker` must be its marker PDA,
+// whether or not the marker account currently exists.
+pub fn is_blocked(program_id: &Pubkey, address: &Pubkey, marker: &AccountInfo) -> Result<bool, ProgramError> {
+    let (pda, _) = marker_address(program_id, address);
+    if pda != *marker.key {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    Ok(marker.owner == program_id && !marker.data_is_empty())
+}
+
+// Creates the marker PDA for `address`, funded by `admin`
+pub fn block<'a>(
+    program_id: &Pubkey,
+    address: &Pubkey,
+    admin: &AccountInfo<'a>,
+    marker: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    rent: &Rent,
+) -> ProgramResult {
+    let (pda, bump_seed) = marker_address(program_id, address);
+    if pda != *marker.key {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    invoke_signed(
+        &system_instruction::create_account(
+            admin.key,
+            marker.key,
+            rent.minimum_balance(1),
+            1,
+            program_id,
+        ),
+        &[admin.clone(), marker.clone(), system_program.clone()],
+        &[&[b"blocked", address.as_ref(), &[bump_seed]]],
+    )?;
+    marker.data.borrow_mut()[0] = 1;
+
+    Ok(())
+}
+
+// Closes the marker PDA for `address`, returning its rent to `admin`
+pub fn unblock(program_id: &Pubkey, address: &Pubkey, admin: &AccountInfo, marker: &AccountInfo) -> ProgramResult {
+    if !is_blocked(program_id, address, marker)? {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    **admin.try_borrow_mut_lamports()? = admin.lamports()
+        .checked_add(marker.lamports())
+        .ok_or(ProgramError::ArithmeticOverflow)?;
+    **marker.try_borrow_mut_lamports()? = 0;
+
+    marker.realloc(0, false)?;
+    marker.assign(&system_program::id());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_program::clock::Epoch;
+
+    #[test]
+    fn test_is_blocked() {
+        let program_id = Pubkey::new_unique();
+        let address = Pubkey::new_unique();
+        let (marker_key, _) = marker_address(&program_id, &address);
+        let system_program_id = system_program::id();
+
+        let mut lamports = 1_000_000;
+        let mut data = vec![1];
+        let marker = AccountInfo::new(&marker_key, false, true, &mut lamports, &mut data, &program_id, false, Epoch::default());
+        assert!(is_blocked(&program_id, &address, &marker).unwrap());
+
+        let mut lamports = 0;
+        let mut data = vec![];
+        let missing = AccountInfo::new(&marker_key, false, true, &mut lamports, &mut data, &system_program_id, false, Epoch::default());
+        assert!(!is_blocked(&program_id, &address, &missing).unwrap());
+
+        // Markers of other addresses are rejected
+        let other = Pubkey::new_unique();
+        assert_eq!(is_blocked(&program_id, &other, &missing), Err(ProgramError::InvalidAccountData));
+    }
+}
@@ -0,0 +1,35 @@
+// Caller checks for programs that restrict which integrators may CPI into them.
+use solana_program::{
+    account_info::AccountInfo,
+    instruction::{get_stack_height, TRANSACTION_LEVEL_STACK_HEIGHT},
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    sysvar::instructions::{self, get_instruction_relative},
+};
+
+// Returns whether the instruction being processed was either sent directly in
+// the transaction or reached via CPI from a top-level instruction of a
+// program in `allowlist`. `instructions_sysvar` must be the Instructions
+// sysvar account.
+//
+// Only the top-level instruction is checked; the runtime exposes no record of
+// the intermediate programs in a CPI chain. A program outside the allowlist
+// therefore passes when it is itself invoked by an allowlisted program, so
+// allowlisted programs must not make arbitrary CPIs on behalf of callers.
+pub fn is_allowed_caller(
+    program_id: &Pubkey,
+    instructions_sysvar: &AccountInfo,
+    allowlist: &[Pubkey],
+) -> Result<bool, ProgramError> {
+    if !instructions::check_id(instructions_sysvar.key) {
+        return Err(ProgramError::UnsupportedSysvar);
+    }
+
+    if get_stack_height() <= TRANSACTION_LEVEL_STACK_HEIGHT {
+        return Ok(true);
+    }
+
+    // Only the outermost program of a CPI chain is visible through the sysvar
+    let top_level = get_instruction_relative(0, instructions_sysvar)?;
+    Ok(top_level.program_id == *program_id || allowlist.contains(&top_level.program_id))
+}
@@ -0,0 +1,50 @@
+// Token amount normalization. Valuation math runs on amounts scaled to a
+// common CANONICAL_DECIMALS precision, so a 6 decimal USDC amount and a 9
+// decimal lamport amount are never mixed in one formula. Amounts are scaled
+// back to the token's own decimals only where they are transferred.
+use super::math::{mul_div, Rounding};
+
+pub const CANONICAL_DECIMALS: u8 = 9;
+
+// Scales `amount` of a token with `decimals` decimals up to canonical precision.
+// Returns None for tokens with more than CANONICAL_DECIMALS decimals or on overflow.
+pub fn to_canonical(amount: u64, decimals: u8) -> Option<u64> {
+    amount.checked_mul(scale(decimals)?)
+}
+
+// Scales a canonical amount back down to a token with `decimals` decimals,
+// rounded as requested
+pub fn from_canonical(amount: u64, decimals: u8, rounding: Rounding) -> Option<u64> {
+    mul_div(amount, 1, scale(decimals)?, rounding)
+}
+
+fn scale(decimals: u8) -> Option<u64> {
+    10u64.checked_pow(CANONICAL_DECIMALS.checked_sub(decimals)? as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        // 1.5 USDC and 1.5 SOL have the same canonical amount
+        assert_eq!(to_canonical(1_500_000, 6), Some(1_500_000_000));
+        assert_eq!(to_canonical(1_500_000_000, 9), Some(1_500_000_000));
+        assert_eq!(from_canonical(1_500_000_000, 6, Rounding::Down), Some(1_500_000));
+        assert_eq!(from_canonical(1_500_000_000, 9, Rounding::Down), Some(1_500_000_000));
+    }
+
+    #[test]
+    fn test_scaling_down_rounds_in_requested_direction() {
+        assert_eq!(from_canonical(1_000_000_001, 6, Rounding::Down), Some(1_000_000));
+        assert_eq!(from_canonical(1_000_000_001, 6, Rounding::Up), Some(1_000_001));
+    }
+
+    #[test]
+    fn test_unsupported_decimals_and_overflow() {
+        assert_eq!(to_canonical(1, 10), None);
+        assert_eq!(from_canonical(1, 10, Rounding::Down), None);
+        assert_eq!(to_canonical(u64::MAX, 6), None);
+    }
+}
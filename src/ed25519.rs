@@ -0,0 +1,120 @@
+// Checks for messages signed off-chain. The runtime's ed25519 program verifies
+// the signature itself; these helpers confirm, through the Instructions
+// sysvar, that it did so for the expected signer and message.
+use solana_program::{
+    account_info::AccountInfo,
+    ed25519_program,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    sysvar::instructions::{self, get_instruction_relative},
+};
+
+// Layout of the ed25519 program's instruction data: a signature count and a
+// padding byte, then one block of seven u16 offsets per signature
+const SIGNATURE_OFFSETS_START: usize = 2;
+const SIGNATURE_OFFSETS_LEN: usize = 14;
+const PUBKEY_LEN: usize = 32;
+
+// Instruction index meaning "the ed25519 instruction's own data"
+const CURRENT_INSTRUCTION: u16 = u16::MAX;
+
+// Returns whether the instruction right before the current one is an ed25519
+// program instruction verifying a single signature by `signer` over
+// `message`. `instructions_sysvar` must be the Instructions sysvar account.
+pub fn is_signed_by(instructions_sysvar: &AccountInfo, signer: &Pubkey, message: &[u8]) -> Result<bool, ProgramError> {
+    if !instructions::check_id(instructions_sysvar.key) {
+        return Err(ProgramError::UnsupportedSysvar);
+    }
+
+    let previous = match get_instruction_relative(-1, instructions_sysvar) {
+        Ok(instruction) => instruction,
+        Err(_) => return Ok(false),
+    };
+    Ok(previous.program_id == ed25519_program::id() && verifies(&previous.data, signer, message))
+}
+
+// Returns whether ed25519 instruction `data` covers exactly one signature,
+// made by `signer` over `message`
+fn verifies(data: &[u8], signer: &Pubkey, message: &[u8]) -> bool {
+    if data.len() < SIGNATURE_OFFSETS_START + SIGNATURE_OFFSETS_LEN || data[0] != 1 {
+        return false;
+    }
+
+    let offset = |i: usize| {
+        let start = SIGNATURE_OFFSETS_START + 2 * i;
+        u16::from_le_bytes([data[start], data[start + 1]])
+    };
+    let signature_instruction = offset(1);
+    let pubkey_offset = offset(2) as usize;
+    let pubkey_instruction = offset(3);
+    let message_offset = offset(4) as usize;
+    let message_len = offset(5) as usize;
+    let message_instruction = offset(6);
+
+    // Offsets into other instructions would let the verified bytes differ
+    // from the ones compared here
+    if [signature_instruction, pubkey_instruction, message_instruction]
+        .iter()
+        .any(|&index| index != CURRENT_INSTRUCTION)
+    {
+        return false;
+    }
+
+    data.get(pubkey_offset..pubkey_offset + PUBKEY_LEN) == Some(signer.as_ref())
+        && data.get(message_offset..message_offset + message_len) == Some(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Builds ed25519 instruction data the way the SDK does: offsets, then
+    // the public key, the signature and the message
+    fn instruction_data(signer: &Pubkey, message: &[u8], index: u16) -> Vec<u8> {
+        let pubkey_offset = (SIGNATURE_OFFSETS_START + SIGNATURE_OFFSETS_LEN) as u16;
+        let signature_offset = pubkey_offset + PUBKEY_LEN as u16;
+        let message_offset = signature_offset + 64;
+
+        let mut data = vec![1, 0];
+        for value in [signature_offset, index, pubkey_offset, index, message_offset, message.len() as u16, index] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data.extend_from_slice(signer.as_ref());
+        data.extend_from_slice(&[0; 64]);
+        data.extend_from_slice(message);
+        data
+    }
+
+    #[test]
+    fn test_verifies() {
+        let signer = Pubkey::new_unique();
+        let message = b"withdraw 500000";
+        let data = instruction_data(&signer, message, CURRENT_INSTRUCTION);
+        assert!(verifies(&data, &signer, message));
+
+        // Another signer or message
+        assert!(!verifies(&data, &Pubkey::new_unique(), message));
+        assert!(!verifies(&data, &signer, b"withdraw 500001"));
+
+        // Key or message taken from another instruction
+        let data = instruction_data(&signer, message, 0);
+        assert!(!verifies(&data, &signer, message));
+    }
+
+    #[test]
+    fn test_verifies_rejects_malformed_data() {
+        let signer = Pubkey::new_unique();
+        let message = b"withdraw 500000";
+
+        let mut data = instruction_data(&signer, message, CURRENT_INSTRUCTION);
+        data[0] = 2;
+        assert!(!verifies(&data, &signer, message));
+
+        // Message running past the end of the data
+        let mut data = instruction_data(&signer, message, CURRENT_INSTRUCTION);
+        data.truncate(data.len() - 1);
+        assert!(!verifies(&data, &signer, message));
+
+        assert!(!verifies(&[1, 0], &signer, message));
+    }
+}
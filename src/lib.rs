@@ -0,0 +1,16 @@
+// Library target for the programs in `src/`. The lending program is deployed
+// from it as `radar_lend.so`; with `no-entrypoint` (or `cpi`) the deposit
+// programs are exported too, so integrators and tests can use every program's
+// instruction, account and error types without linking an entrypoint.
+#[path = "main_usdc_sol_collateral.rs"]
+pub mod lending;
+
+// Each of these declares its own entrypoint, so they can only share the
+// library with the lending program when entrypoints are compiled out
+#[cfg(feature = "no-entrypoint")]
+#[path = "main_deposit.rs"]
+pub mod deposit;
+
+#[cfg(feature = "no-entrypoint")]
+#[path = "main_deposit_withdraw.rs"]
+pub mod deposit_withdraw;
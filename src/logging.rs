@@ -0,0 +1,11 @@
+// Informational logging that costs no compute unless the `debug-logs`
+// feature is enabled. Errors and admin actions are logged with `msg!`
+// unconditionally; instruction outcomes are available to CPI callers as
+// return data, so their log lines are debug-only.
+macro_rules! debug_msg {
+    ($($arg:tt)*) => {
+        if cfg!(feature = "debug-logs") {
+            solana_program::msg!($($arg)*);
+        }
+    };
+}
@@ -1,8 +1,6 @@
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
-    entrypoint,
     entrypoint::ProgramResult,
-    msg,
     program_error::ProgramError,
     pubkey::Pubkey,
     rent::Rent,
@@ -11,6 +9,9 @@ use solana_program::{
 use borsh::{BorshDeserialize, BorshSerialize};
 use thiserror::Error;
 
+#[macro_use]
+mod logging;
+
 // Define the program ID
 solana_program::declare_id!("Your_Program_ID_Here");
 
@@ -37,17 +38,21 @@ pub enum DepositError {
     #[error("Expected amount to be greater than zero")]
     AmountMustBeGreaterThanZero,
 
-    #[error("Deposit overflow")]
+    #[error("Arithmetic overflow")]
     Overflow,
 }
 
+// DepositError codes start at 1000 (see Error Codes in the README)
+const ERROR_CODE_BASE: u32 = 1000;
+
 impl From<DepositError> for ProgramError {
     fn from(e: DepositError) -> Self {
-        ProgramError::Custom(e as u32)
+        ProgramError::Custom(ERROR_CODE_BASE + e as u32)
     }
 }
 
-entrypoint!(process_instruction);
+#[cfg(not(feature = "no-entrypoint"))]
+solana_program::entrypoint!(process_instruction);
 
 pub fn process_instruction(
     program_id: &Pubkey,
@@ -86,7 +91,7 @@ fn initialize_account(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramR
     account_data.balance = 0;
     account_data.serialize(&mut &mut user_account.data.borrow_mut()[..])?;
 
-    msg!("Account initialized");
+    debug_msg!("Account initialized");
     Ok(())
 }
 
@@ -122,7 +127,7 @@ fn deposit(accounts: &[AccountInfo], amount: u64) -> ProgramResult {
         .checked_add(amount)
         .ok_or(DepositError::Overflow)?;
 
-    msg!("Deposit successful: {} lamports", amount);
+    debug_msg!("Deposit successful: {} lamports", amount);
     Ok(())
 }
 
@@ -1,30 +1,100 @@
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
-    entrypoint,
+    clock::Clock,
     entrypoint::ProgramResult,
+    instruction::{AccountMeta, Instruction},
     msg,
+    program::invoke,
     program_error::ProgramError,
     pubkey::Pubkey,
     rent::Rent,
+    system_instruction,
     sysvar::Sysvar,
 };
 use borsh::{BorshDeserialize, BorshSerialize};
 use thiserror::Error;
 
+#[macro_use]
+mod logging;
+
+pub mod audit_log;
+mod blocklist;
+mod cpi_guard;
+mod ed25519;
+#[cfg(test)]
+mod test_utils;
+
+use audit_log::{AuditAction, AuditEntry};
+
 // Define the program ID
 solana_program::declare_id!("Your_Program_ID_Here");
 
+const ADMIN: Pubkey = solana_program::pubkey!("Your_Admin_Address_Here");
+
+// Programs allowed to withdraw via CPI (see Configuration in the README)
+const CPI_ALLOWLIST: Option<&[Pubkey]> = None;
+
+// Withdraw amount meaning "the whole balance at execution time"
+const WITHDRAW_ALL: u64 = u64::MAX;
+
 #[derive(BorshSerialize, BorshDeserialize, Debug)]
 pub struct UserAccount {
     pub owner: Pubkey,
     pub balance: u64,
+    // Incremented by every deposit and withdrawal
+    pub nonce: u64,
 }
 
+pub const USER_ACCOUNT_LEN: usize = 48;
+
+// Accounts created before `nonce` was added are this long. They have to be
+// grown with MigrateAccount before they can be used again.
+pub const LEGACY_USER_ACCOUNT_LEN: usize = 40;
+
 #[derive(BorshSerialize, BorshDeserialize, Debug)]
 pub enum DepositWithdrawInstruction {
     InitializeAccount,
-    Deposit { amount: u64 },
-    Withdraw { amount: u64 },
+    // `expected_nonce`, if set, must equal the account nonce, so a relayed
+    // intent executes at most once and in order
+    Deposit { amount: u64, expected_nonce: Option<u64> },
+    Withdraw { amount: u64, expected_nonce: Option<u64> },
+    BlockAddress { address: Pubkey },
+    UnblockAddress { address: Pubkey },
+    // Executes a WithdrawIntent signed off-chain by the account owner. The
+    // transaction must carry the ed25519 program instruction verifying the
+    // signature immediately before this one; the relayer pays the fee.
+    RelayedWithdraw { amount: u64, nonce: u64, expiry: i64 },
+    InitializeAuditLog,
+    MigrateAccount,
+}
+
+// Builds a Deposit of `amount` lamports from `user` into `user_account`, for
+// clients and for programs composing with this one over CPI. With
+// `with_audit_log`, the deposit is also recorded in the user's audit log.
+pub fn deposit_instruction(
+    program_id: &Pubkey,
+    user_account: &Pubkey,
+    user: &Pubkey,
+    amount: u64,
+    expected_nonce: Option<u64>,
+    with_audit_log: bool,
+) -> Instruction {
+    let mut accounts = vec![AccountMeta::new(*user_account, false), AccountMeta::new(*user, true)];
+    if with_audit_log {
+        accounts.push(AccountMeta::new(audit_log::log_address(program_id, user).0, false));
+    }
+    Instruction::new_with_borsh(*program_id, &DepositWithdrawInstruction::Deposit { amount, expected_nonce }, accounts)
+}
+
+// Off-chain withdraw approval. The owner signs its Borsh serialization; it is
+// only valid for this program and user account, at `nonce`, until `expiry`.
+#[derive(BorshSerialize, BorshDeserialize, Debug)]
+pub struct WithdrawIntent {
+    pub program_id: Pubkey,
+    pub user_account: Pubkey,
+    pub amount: u64,
+    pub nonce: u64,
+    pub expiry: i64,
 }
 
 #[derive(Error, Debug)]
@@ -43,15 +113,40 @@ pub enum DepositWithdrawError {
 
     #[error("Arithmetic overflow")]
     Overflow,
+
+    #[error("Calling program is not allowed to invoke this instruction")]
+    UnauthorizedCpiCaller,
+
+    #[error("Signer is not the program admin")]
+    Unauthorized,
+
+    #[error("Address is blocked")]
+    AddressBlocked,
+
+    #[error("Nonce does not match the account")]
+    NonceMismatch,
+
+    #[error("Missing or invalid intent signature")]
+    InvalidIntentSignature,
+
+    #[error("Intent has expired")]
+    IntentExpired,
+
+    #[error("User account uses the legacy layout and must be migrated")]
+    AccountNeedsMigration,
 }
 
+// DepositWithdrawError codes start at 2000 (see Error Codes in the README)
+const ERROR_CODE_BASE: u32 = 2000;
+
 impl From<DepositWithdrawError> for ProgramError {
     fn from(e: DepositWithdrawError) -> Self {
-        ProgramError::Custom(e as u32)
+        ProgramError::Custom(ERROR_CODE_BASE + e as u32)
     }
 }
 
-entrypoint!(process_instruction);
+#[cfg(not(feature = "no-entrypoint"))]
+solana_program::entrypoint!(process_instruction);
 
 pub fn process_instruction(
     program_id: &Pubkey,
@@ -63,8 +158,19 @@ pub fn process_instruction(
 
     match instruction {
         DepositWithdrawInstruction::InitializeAccount => initialize_account(program_id, accounts),
-        DepositWithdrawInstruction::Deposit { amount } => deposit(accounts, amount),
-        DepositWithdrawInstruction::Withdraw { amount } => withdraw(accounts, amount),
+        DepositWithdrawInstruction::Deposit { amount, expected_nonce } => {
+            deposit(program_id, accounts, amount, expected_nonce)
+        }
+        DepositWithdrawInstruction::Withdraw { amount, expected_nonce } => {
+            withdraw(program_id, accounts, amount, expected_nonce)
+        }
+        DepositWithdrawInstruction::BlockAddress { address } => block_address(program_id, accounts, address),
+        DepositWithdrawInstruction::UnblockAddress { address } => unblock_address(program_id, accounts, address),
+        DepositWithdrawInstruction::RelayedWithdraw { amount, nonce, expiry } => {
+            relayed_withdraw(program_id, accounts, amount, nonce, expiry)
+        }
+        DepositWithdrawInstruction::InitializeAuditLog => initialize_audit_log(program_id, accounts),
+        DepositWithdrawInstruction::MigrateAccount => migrate_account(program_id, accounts),
     }
 }
 
@@ -89,13 +195,67 @@ fn initialize_account(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramR
     let mut account_data = UserAccount::try_from_slice(&user_account.data.borrow())?;
     account_data.owner = *user.key;
     account_data.balance = 0;
+    account_data.nonce = 0;
+    account_data.serialize(&mut &mut user_account.data.borrow_mut()[..])?;
+
+    debug_msg!("Account initialized");
+    Ok(())
+}
+
+// Deserializes a user account, failing with AccountNeedsMigration for one in
+// the legacy layout, whose nonce could not be stored
+fn load_user_account(user_account: &AccountInfo) -> Result<UserAccount, ProgramError> {
+    if user_account.data_len() == LEGACY_USER_ACCOUNT_LEN {
+        msg!("User account is {} bytes, migrate it to {} bytes first", LEGACY_USER_ACCOUNT_LEN, USER_ACCOUNT_LEN);
+        return Err(DepositWithdrawError::AccountNeedsMigration.into());
+    }
+
+    let account_data = UserAccount::try_from_slice(&user_account.data.borrow())?;
+    Ok(account_data)
+}
+
+// Grows a legacy user account to the current layout, starting its nonce at 0.
+// The owner pays the rent for the added bytes.
+fn migrate_account(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let user_account = next_account_info(account_info_iter)?;
+    let user = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+    let rent = &Rent::from_account_info(next_account_info(account_info_iter)?)?;
+
+    if !user.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if user_account.owner != program_id {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    if user_account.data_len() != LEGACY_USER_ACCOUNT_LEN {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let owner = Pubkey::try_from_slice(&user_account.data.borrow()[..32])?;
+    let balance = u64::try_from_slice(&user_account.data.borrow()[32..])?;
+    if owner != *user.key {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let extra_rent = rent.minimum_balance(USER_ACCOUNT_LEN) - rent.minimum_balance(LEGACY_USER_ACCOUNT_LEN);
+    invoke(
+        &system_instruction::transfer(user.key, user_account.key, extra_rent),
+        &[user.clone(), user_account.clone(), system_program.clone()],
+    )?;
+
+    user_account.realloc(USER_ACCOUNT_LEN, false)?;
+    let account_data = UserAccount { owner, balance, nonce: 0 };
     account_data.serialize(&mut &mut user_account.data.borrow_mut()[..])?;
 
-    msg!("Account initialized");
+    debug_msg!("Account migrated");
     Ok(())
 }
 
-fn deposit(accounts: &[AccountInfo], amount: u64) -> ProgramResult {
+fn deposit(program_id: &Pubkey, accounts: &[AccountInfo], amount: u64, expected_nonce: Option<u64>) -> ProgramResult {
     let account_info_iter = &mut accounts.iter();
     let user_account = next_account_info(account_info_iter)?;
     let user = next_account_info(account_info_iter)?;
@@ -108,12 +268,14 @@ fn deposit(accounts: &[AccountInfo], amount: u64) -> ProgramResult {
         return Err(ProgramError::MissingRequiredSignature);
     }
 
-    let mut account_data = UserAccount::try_from_slice(&user_account.data.borrow())?;
+    let mut account_data = load_user_account(user_account)?;
 
     if account_data.owner != *user.key {
         return Err(ProgramError::InvalidAccountData);
     }
 
+    use_nonce(&mut account_data, expected_nonce)?;
+
     account_data.balance = account_data.balance.checked_add(amount)
         .ok_or(DepositWithdrawError::Overflow)?;
 
@@ -127,14 +289,43 @@ fn deposit(accounts: &[AccountInfo], amount: u64) -> ProgramResult {
         .checked_add(amount)
         .ok_or(DepositWithdrawError::Overflow)?;
 
-    msg!("Deposit successful: {} lamports", amount);
+    // The user's audit log is an optional trailing account
+    if let Some(audit_log) = account_info_iter.next() {
+        let timestamp = Clock::get()?.unix_timestamp;
+        audit_log::record(program_id, user.key, audit_log, &AuditEntry { action: AuditAction::Deposit, amount, timestamp })?;
+    }
+
+    debug_msg!("Deposit successful: {} lamports", amount);
     Ok(())
 }
 
-fn withdraw(accounts: &[AccountInfo], amount: u64) -> ProgramResult {
+// Checks `expected_nonce` against the account, if given, and advances the nonce
+fn use_nonce(account_data: &mut UserAccount, expected_nonce: Option<u64>) -> ProgramResult {
+    if let Some(expected_nonce) = expected_nonce {
+        if expected_nonce != account_data.nonce {
+            msg!("Nonce mismatch: expected {}, account is at {}", expected_nonce, account_data.nonce);
+            return Err(DepositWithdrawError::NonceMismatch.into());
+        }
+    }
+
+    account_data.nonce = account_data.nonce.checked_add(1)
+        .ok_or(DepositWithdrawError::Overflow)?;
+    Ok(())
+}
+
+fn withdraw(program_id: &Pubkey, accounts: &[AccountInfo], amount: u64, expected_nonce: Option<u64>) -> ProgramResult {
     let account_info_iter = &mut accounts.iter();
     let user_account = next_account_info(account_info_iter)?;
     let user = next_account_info(account_info_iter)?;
+    let blocklist_marker = next_account_info(account_info_iter)?;
+    let rent = &Rent::from_account_info(next_account_info(account_info_iter)?)?;
+
+    if let Some(allowlist) = CPI_ALLOWLIST {
+        let instructions_sysvar = next_account_info(account_info_iter)?;
+        if !cpi_guard::is_allowed_caller(program_id, instructions_sysvar, allowlist)? {
+            return Err(DepositWithdrawError::UnauthorizedCpiCaller.into());
+        }
+    }
 
     if amount == 0 {
         return Err(DepositWithdrawError::AmountMustBeGreaterThanZero.into());
@@ -144,37 +335,191 @@ fn withdraw(accounts: &[AccountInfo], amount: u64) -> ProgramResult {
         return Err(ProgramError::MissingRequiredSignature);
     }
 
-    let mut account_data = UserAccount::try_from_slice(&user_account.data.borrow())?;
+    if blocklist::is_blocked(program_id, user.key, blocklist_marker)? {
+        return Err(DepositWithdrawError::AddressBlocked.into());
+    }
+
+    let mut account_data = load_user_account(user_account)?;
 
     if account_data.owner != *user.key {
         return Err(ProgramError::InvalidAccountData);
     }
 
+    use_nonce(&mut account_data, expected_nonce)?;
+
+    let amount = pay_out(user_account, user, account_data, amount, rent)?;
+
+    if let Some(audit_log) = account_info_iter.next() {
+        let timestamp = Clock::get()?.unix_timestamp;
+        audit_log::record(program_id, user.key, audit_log, &AuditEntry { action: AuditAction::Withdraw, amount, timestamp })?;
+    }
+    Ok(())
+}
+
+// Withdraws `amount` (or the whole balance, for WITHDRAW_ALL) from an
+// authorized user account to `user`, returning the amount paid out
+fn pay_out(
+    user_account: &AccountInfo,
+    user: &AccountInfo,
+    mut account_data: UserAccount,
+    amount: u64,
+    rent: &Rent,
+) -> Result<u64, ProgramError> {
+    let amount = if amount == WITHDRAW_ALL { account_data.balance } else { amount };
+    // Withdrawing everything from an empty account would be a silent no-op
+    if amount == 0 {
+        return Err(DepositWithdrawError::AmountMustBeGreaterThanZero.into());
+    }
+
     if account_data.balance < amount {
+        msg!("Insufficient balance: requested {} lamports, balance {} lamports", amount, account_data.balance);
         return Err(DepositWithdrawError::InsufficientBalance.into());
     }
 
+    // The account has to stay rent exempt after the withdrawal
+    let remaining = user_account.lamports()
+        .checked_sub(amount)
+        .ok_or(ProgramError::InsufficientFunds)?;
+    let minimum_balance = rent.minimum_balance(user_account.data_len());
+    if remaining < minimum_balance {
+        msg!("Withdrawal would leave {} lamports, rent exemption needs {} lamports", remaining, minimum_balance);
+        return Err(DepositWithdrawError::NotRentExempt.into());
+    }
+
     account_data.balance = account_data.balance.checked_sub(amount)
         .ok_or(DepositWithdrawError::Overflow)?;
 
     account_data.serialize(&mut &mut user_account.data.borrow_mut()[..])?;
 
-    **user_account.try_borrow_mut_lamports()? = user_account.lamports()
-        .checked_sub(amount)
-        .ok_or(ProgramError::InsufficientFunds)?;
+    **user_account.try_borrow_mut_lamports()? = remaining;
 
     **user.try_borrow_mut_lamports()? = user.lamports()
         .checked_add(amount)
         .ok_or(DepositWithdrawError::Overflow)?;
 
-    msg!("Withdrawal successful: {} lamports", amount);
+    debug_msg!("Withdrawal successful: {} lamports", amount);
+    Ok(amount)
+}
+
+fn relayed_withdraw(program_id: &Pubkey, accounts: &[AccountInfo], amount: u64, nonce: u64, expiry: i64) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let user_account = next_account_info(account_info_iter)?;
+    let user = next_account_info(account_info_iter)?;
+    let blocklist_marker = next_account_info(account_info_iter)?;
+    let rent = &Rent::from_account_info(next_account_info(account_info_iter)?)?;
+    let clock = &Clock::from_account_info(next_account_info(account_info_iter)?)?;
+    let instructions_sysvar = next_account_info(account_info_iter)?;
+
+    if let Some(allowlist) = CPI_ALLOWLIST {
+        if !cpi_guard::is_allowed_caller(program_id, instructions_sysvar, allowlist)? {
+            return Err(DepositWithdrawError::UnauthorizedCpiCaller.into());
+        }
+    }
+
+    if amount == 0 {
+        return Err(DepositWithdrawError::AmountMustBeGreaterThanZero.into());
+    }
+
+    if clock.unix_timestamp > expiry {
+        msg!("Intent expired at {}, now {}", expiry, clock.unix_timestamp);
+        return Err(DepositWithdrawError::IntentExpired.into());
+    }
+
+    if blocklist::is_blocked(program_id, user.key, blocklist_marker)? {
+        return Err(DepositWithdrawError::AddressBlocked.into());
+    }
+
+    let mut account_data = load_user_account(user_account)?;
+
+    if account_data.owner != *user.key {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    // The owner's off-chain signature stands in for their transaction signature
+    let intent = WithdrawIntent {
+        program_id: *program_id,
+        user_account: *user_account.key,
+        amount,
+        nonce,
+        expiry,
+    };
+    if !ed25519::is_signed_by(instructions_sysvar, user.key, &intent.try_to_vec()?)? {
+        return Err(DepositWithdrawError::InvalidIntentSignature.into());
+    }
+
+    use_nonce(&mut account_data, Some(nonce))?;
+
+    let amount = pay_out(user_account, user, account_data, amount, rent)?;
+
+    if let Some(audit_log) = account_info_iter.next() {
+        let timestamp = clock.unix_timestamp;
+        audit_log::record(program_id, user.key, audit_log, &AuditEntry { action: AuditAction::RelayedWithdraw, amount, timestamp })?;
+    }
+    Ok(())
+}
+
+fn initialize_audit_log(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let user = next_account_info(account_info_iter)?;
+    let audit_log = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+    let rent = &Rent::from_account_info(next_account_info(account_info_iter)?)?;
+
+    if !user.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    audit_log::create(program_id, user, audit_log, system_program, rent)?;
+
+    debug_msg!("Audit log initialized");
+    Ok(())
+}
+
+fn block_address(program_id: &Pubkey, accounts: &[AccountInfo], address: Pubkey) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let admin = next_account_info(account_info_iter)?;
+    let blocklist_marker = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+    let rent = &Rent::from_account_info(next_account_info(account_info_iter)?)?;
+
+    if !admin.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if *admin.key != ADMIN {
+        return Err(DepositWithdrawError::Unauthorized.into());
+    }
+
+    blocklist::block(program_id, &address, admin, blocklist_marker, system_program, rent)?;
+
+    msg!("Address blocked: {}", address);
+    Ok(())
+}
+
+fn unblock_address(program_id: &Pubkey, accounts: &[AccountInfo], address: Pubkey) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let admin = next_account_info(account_info_iter)?;
+    let blocklist_marker = next_account_info(account_info_iter)?;
+
+    if !admin.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if *admin.key != ADMIN {
+        return Err(DepositWithdrawError::Unauthorized.into());
+    }
+
+    blocklist::unblock(program_id, &address, admin, blocklist_marker)?;
+
+    msg!("Address unblocked: {}", address);
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use solana_program::clock::Epoch;
+    use super::test_utils::{clock_sysvar_data, rent_sysvar_data, serialize_input, test_account};
+    use solana_program::{clock::Epoch, entrypoint};
     use std::mem;
 
     #[test]
@@ -264,9 +609,253 @@ mod tests {
         let mut account_data = UserAccount {
             owner: user_key,
             balance: 0,
+            nonce: 0,
         };
         account_data.serialize(&mut &mut user_account.data.borrow_mut()[..]).unwrap();
 
         let accounts = vec![user_account.clone(), user.clone()];
     }
+
+    #[test]
+    fn test_withdraw_all() {
+        let rent = Rent::default();
+        let mut fixture = WithdrawFixture::new(500_000, rent.minimum_balance(mem::size_of::<UserAccount>()) + 500_000);
+        let program_id = fixture.program_id;
+        let accounts = fixture.accounts();
+        withdraw(&program_id, &accounts[..4], WITHDRAW_ALL, Some(0)).unwrap();
+
+        let account_data = UserAccount::try_from_slice(&accounts[0].data.borrow()).unwrap();
+        assert_eq!(account_data.balance, 0);
+        assert_eq!(account_data.nonce, 1);
+        assert_eq!(accounts[0].lamports(), rent.minimum_balance(mem::size_of::<UserAccount>()));
+        assert_eq!(accounts[1].lamports(), 500_000);
+    }
+
+    #[test]
+    fn test_withdraw_all_rejects_empty_balance() {
+        let rent = Rent::default();
+        let mut fixture = WithdrawFixture::new(0, rent.minimum_balance(mem::size_of::<UserAccount>()));
+        let program_id = fixture.program_id;
+        let accounts = fixture.accounts();
+        let result = withdraw(&program_id, &accounts[..4], WITHDRAW_ALL, None);
+        assert_eq!(result, Err(DepositWithdrawError::AmountMustBeGreaterThanZero.into()));
+
+        let account_data = UserAccount::try_from_slice(&accounts[0].data.borrow()).unwrap();
+        assert_eq!(account_data.nonce, 0);
+    }
+
+    #[test]
+    fn test_withdraw_keeps_account_rent_exempt() {
+        let rent = Rent::default();
+
+        // Lamports short of the recorded balance plus rent
+        let mut fixture = WithdrawFixture::new(500_000, rent.minimum_balance(mem::size_of::<UserAccount>()) + 400_000);
+        let program_id = fixture.program_id;
+        let accounts = fixture.accounts();
+        let result = withdraw(&program_id, &accounts[..4], 500_000, None);
+        assert_eq!(result, Err(DepositWithdrawError::NotRentExempt.into()));
+
+        // Nothing moved
+        let account_data = UserAccount::try_from_slice(&accounts[0].data.borrow()).unwrap();
+        assert_eq!(account_data.balance, 500_000);
+        assert_eq!(account_data.nonce, 0);
+        assert_eq!(accounts[1].lamports(), 0);
+    }
+
+    #[test]
+    fn test_use_nonce() {
+        let mut account_data = UserAccount {
+            owner: Pubkey::new_unique(),
+            balance: 0,
+            nonce: 5,
+        };
+
+        // Unchecked operations still advance the nonce
+        use_nonce(&mut account_data, None).unwrap();
+        assert_eq!(account_data.nonce, 6);
+
+        use_nonce(&mut account_data, Some(6)).unwrap();
+        assert_eq!(account_data.nonce, 7);
+
+        // A replayed or out-of-order intent is rejected
+        assert_eq!(use_nonce(&mut account_data, Some(6)), Err(DepositWithdrawError::NonceMismatch.into()));
+        assert_eq!(use_nonce(&mut account_data, Some(8)), Err(DepositWithdrawError::NonceMismatch.into()));
+        assert_eq!(account_data.nonce, 7);
+    }
+
+    #[test]
+    fn test_relayed_withdraw_rejects_expired_intent() {
+        let rent = Rent::default();
+        let mut fixture = WithdrawFixture::new(500_000, rent.minimum_balance(mem::size_of::<UserAccount>()) + 500_000);
+
+        // The owner does not sign relayed withdrawals
+        fixture.user_is_signer = false;
+        let program_id = fixture.program_id;
+        let accounts = fixture.accounts();
+        let result = relayed_withdraw(&program_id, &accounts, 500_000, 0, 1_700_000_000);
+        assert_eq!(result, Err(DepositWithdrawError::IntentExpired.into()));
+
+        let account_data = UserAccount::try_from_slice(&accounts[0].data.borrow()).unwrap();
+        assert_eq!(account_data.balance, 500_000);
+        assert_eq!(account_data.nonce, 0);
+    }
+
+    #[test]
+    fn test_withdraw_rejects_legacy_account() {
+        let rent = Rent::default();
+        let mut fixture = WithdrawFixture::new(500_000, rent.minimum_balance(LEGACY_USER_ACCOUNT_LEN) + 500_000);
+        fixture.data[0] = legacy_user_account_data(&fixture.keys[1], 500_000);
+        let program_id = fixture.program_id;
+        let accounts = fixture.accounts();
+        let result = withdraw(&program_id, &accounts[..4], 500_000, None);
+        assert_eq!(result, Err(DepositWithdrawError::AccountNeedsMigration.into()));
+        assert_eq!(accounts[1].lamports(), 0);
+    }
+
+    #[test]
+    fn test_migrate_account() {
+        let program_id = Pubkey::new_unique();
+        let user_key = Pubkey::new_unique();
+        let system_program_id = solana_program::system_program::id();
+        let rent = Rent::default();
+
+        // CPIs do not run outside the runtime, so the rent top-up is not observed
+        let mut input = serialize_input(
+            &program_id,
+            &[
+                test_account(
+                    Pubkey::new_unique(),
+                    false,
+                    rent.minimum_balance(LEGACY_USER_ACCOUNT_LEN) + 500_000,
+                    legacy_user_account_data(&user_key, 500_000),
+                    program_id,
+                ),
+                test_account(user_key, true, 1_000_000, vec![], system_program_id),
+                test_account(system_program_id, false, 0, vec![], system_program_id),
+                test_account(solana_program::sysvar::rent::id(), false, 0, rent_sysvar_data(&rent), system_program_id),
+            ],
+            &DepositWithdrawInstruction::MigrateAccount.try_to_vec().unwrap(),
+        );
+        let (program_id, accounts, instruction_data) = unsafe { entrypoint::deserialize(input.as_mut_ptr() as *mut u8) };
+
+        // Until migrated the account cannot be used
+        let deposit = DepositWithdrawInstruction::Deposit { amount: 100, expected_nonce: Some(0) }.try_to_vec().unwrap();
+        let result = process_instruction(program_id, &accounts[..2], &deposit);
+        assert_eq!(result, Err(DepositWithdrawError::AccountNeedsMigration.into()));
+
+        process_instruction(program_id, &accounts, instruction_data).unwrap();
+
+        let account_data = UserAccount::try_from_slice(&accounts[0].data.borrow()).unwrap();
+        assert_eq!(accounts[0].data_len(), USER_ACCOUNT_LEN);
+        assert_eq!(account_data.owner, user_key);
+        assert_eq!(account_data.balance, 500_000);
+        assert_eq!(account_data.nonce, 0);
+
+        // Deposits work again, starting from nonce 0
+        process_instruction(program_id, &accounts[..2], &deposit).unwrap();
+        let account_data = UserAccount::try_from_slice(&accounts[0].data.borrow()).unwrap();
+        assert_eq!(account_data.balance, 500_100);
+        assert_eq!(account_data.nonce, 1);
+    }
+
+    #[test]
+    fn test_deposit_instruction() {
+        let program_id = Pubkey::new_unique();
+        let user_account_key = Pubkey::new_unique();
+        let user_key = Pubkey::new_unique();
+
+        let instruction = deposit_instruction(&program_id, &user_account_key, &user_key, 100, Some(3), true);
+        assert_eq!(instruction.program_id, program_id);
+        assert_eq!(
+            instruction.accounts,
+            vec![
+                AccountMeta::new(user_account_key, false),
+                AccountMeta::new(user_key, true),
+                AccountMeta::new(audit_log::log_address(&program_id, &user_key).0, false),
+            ]
+        );
+        match DepositWithdrawInstruction::try_from_slice(&instruction.data).unwrap() {
+            DepositWithdrawInstruction::Deposit { amount, expected_nonce } => {
+                assert_eq!(amount, 100);
+                assert_eq!(expected_nonce, Some(3));
+            }
+            _ => panic!("expected a Deposit"),
+        }
+
+        let instruction = deposit_instruction(&program_id, &user_account_key, &user_key, 100, None, false);
+        assert_eq!(instruction.accounts.len(), 2);
+    }
+
+    // User account data as written before `nonce` was added
+    fn legacy_user_account_data(owner: &Pubkey, balance: u64) -> Vec<u8> {
+        let mut data = owner.to_bytes().to_vec();
+        data.extend_from_slice(&balance.to_le_bytes());
+        data
+    }
+
+    // Accounts of a relayed withdrawal: user account, owner, blocklist marker
+    // and the rent, clock (at 1_700_000_001) and instructions sysvars. A plain
+    // withdrawal takes the first four.
+    struct WithdrawFixture {
+        program_id: Pubkey,
+        user_is_signer: bool,
+        keys: [Pubkey; 6],
+        lamports: [u64; 6],
+        data: [Vec<u8>; 6],
+        owners: [Pubkey; 6],
+    }
+
+    impl WithdrawFixture {
+        // An unblocked user whose account records `balance` and holds `account_lamports`
+        fn new(balance: u64, account_lamports: u64) -> Self {
+            let program_id = Pubkey::new_unique();
+            let user_key = Pubkey::new_unique();
+            let (blocklist_marker_key, _) = blocklist::marker_address(&program_id, &user_key);
+            let system_program_id = solana_program::system_program::id();
+            let account_data = UserAccount {
+                owner: user_key,
+                balance,
+                nonce: 0,
+            };
+
+            WithdrawFixture {
+                program_id,
+                user_is_signer: true,
+                keys: [
+                    Pubkey::new_unique(),
+                    user_key,
+                    blocklist_marker_key,
+                    solana_program::sysvar::rent::id(),
+                    solana_program::sysvar::clock::id(),
+                    solana_program::sysvar::instructions::id(),
+                ],
+                lamports: [account_lamports, 0, 0, 0, 0, 0],
+                data: [
+                    account_data.try_to_vec().unwrap(),
+                    vec![],
+                    vec![],
+                    rent_sysvar_data(&Rent::default()),
+                    clock_sysvar_data(1_700_000_001),
+                    vec![],
+                ],
+                owners: [program_id, system_program_id, system_program_id, system_program_id, system_program_id, system_program_id],
+            }
+        }
+
+        fn accounts(&mut self) -> Vec<AccountInfo<'_>> {
+            let user_is_signer = self.user_is_signer;
+            self.keys
+                .iter()
+                .zip(self.lamports.iter_mut())
+                .zip(self.data.iter_mut())
+                .zip(self.owners.iter())
+                .enumerate()
+                .map(|(i, (((key, lamports), data), owner))| {
+                    let is_signer = i == 1 && user_is_signer;
+                    let is_writable = i < 2;
+                    AccountInfo::new(key, is_signer, is_writable, lamports, data, owner, false, Epoch::default())
+                })
+                .collect()
+        }
+    }
 }
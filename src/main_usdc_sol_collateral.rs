@@ -1,19 +1,34 @@
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
-    entrypoint,
     entrypoint::ProgramResult,
     msg,
     program_error::ProgramError,
+    program_pack::Pack,
     pubkey::Pubkey,
     rent::Rent,
     sysvar::{clock::Clock, Sysvar},
-    program::{invoke, invoke_signed},
+    program::{invoke, invoke_signed, set_return_data},
+    instruction::{AccountMeta, Instruction},
     system_instruction,
+    system_program,
 };
-use spl_token::instruction as token_instruction;
+use spl_token::{instruction as token_instruction, state::Account as TokenAccount};
 use borsh::{BorshDeserialize, BorshSerialize};
 use thiserror::Error;
 
+#[macro_use]
+mod logging;
+
+mod blocklist;
+mod cpi_guard;
+mod decimals;
+mod math;
+#[cfg(test)]
+mod test_utils;
+
+use decimals::{from_canonical, to_canonical};
+use math::{mul_div, Rounding};
+
 // Define the program ID
 solana_program::declare_id!("Your_Program_ID_Here");
 
@@ -21,9 +36,64 @@ solana_program::declare_id!("Your_Program_ID_Here");
 const SOL_PRICE: u64 = 150;  // $150 per SOL
 const LTV: u64 = 25;  // 25% LTV
 const USDC_DECIMALS: u8 = 6;
-const USDC_MINT: Pubkey = solana_program::pubkey!("Your_USDC_Mint_Address_Here");
-const PROGRAM_USDC_ACCOUNT: Pubkey = solana_program::pubkey!("Your_Program_USDC_Account_Here");
+const SOL_DECIMALS: u8 = 9;
+pub const USDC_MINT: Pubkey = solana_program::pubkey!("Your_USDC_Mint_Address_Here");
+const ADMIN: Pubkey = solana_program::pubkey!("Your_Admin_Address_Here");
+// USDC liquidity of the USDC market. Must be owned by the authority PDA.
+pub const PROGRAM_USDC_ACCOUNT: Pubkey = solana_program::pubkey!("Your_Program_USDC_Account_Here");
+pub const TREASURY_USDC_ACCOUNT: Pubkey = solana_program::pubkey!("Your_Treasury_USDC_Account_Here");
+const TREASURY_SOL_ACCOUNT: Pubkey = solana_program::pubkey!("Your_Treasury_SOL_Account_Here");
+const ORIGINATION_FEE_BPS: u64 = 50;  // 0.5% origination fee
+const ORIGINATION_FEE_MODE: OriginationFeeMode = OriginationFeeMode::DeductFromDisbursement;
+const BPS_DENOMINATOR: u64 = 10_000;
+// Discount on the collateral's value a liquidator pays, so liquidating is
+// worth the transaction and the rent of a bad debt record
+const LIQUIDATION_BONUS_BPS: u64 = 500;  // 5%
+// Prepayment fee tiers as (loan age in seconds, fee in bps), ascending by age.
+// Principal repaid before the loan reaches a tier's age pays that tier's fee.
+// Leave empty to disable prepayment fees.
+const PREPAYMENT_FEE_SCHEDULE: &[(i64, u64)] = &[];
+const MIN_LOAN_PRINCIPAL: u64 = 10_000_000;  // 10 USDC
+const MIN_SOL_LOAN_PRINCIPAL: u64 = 100_000_000;  // 0.1 SOL
+const DUST_THRESHOLD: u64 = 10_000;  // 0.01 USDC
+const SOL_DUST_THRESHOLD: u64 = 100_000;  // 0.0001 SOL
+const SECONDS_PER_YEAR: u64 = 365 * 24 * 60 * 60;
+// Programs allowed to originate loans via CPI (see Configuration in the README)
+const CPI_ALLOWLIST: Option<&[Pubkey]> = None;
+const SOL_LOAN_LTV: u64 = 50;  // 50% LTV when borrowing SOL against USDC
+// Credential token (e.g. a non-transferable KYC token) borrowers must hold to
+// originate loans. None runs a permissionless pool; Some requires the
+// borrower's credential token account after the bad debt account.
+const REQUIRED_CREDENTIAL_MINT: Option<Pubkey> = None;
+const EMERGENCY_SWEEP_DELAY: i64 = 7 * 24 * 60 * 60;  // 7 days between announcing and executing a sweep
+
+// How the origination fee is charged to the borrower
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OriginationFeeMode {
+    // Borrower owes the requested amount but receives it minus the fee
+    DeductFromDisbursement,
+    // Borrower receives the requested amount and owes it plus the fee
+    AddToPrincipal,
+}
+
+// Principal owed, USDC paid out to the borrower and fee sent to the treasury
+#[derive(Debug, PartialEq)]
+pub struct LoanTerms {
+    pub principal: u64,
+    pub disbursed: u64,
+    pub origination_fee: u64,
+}
 
+// Loan accounts are fixed-width Borsh. The same layout backs both markets:
+// for USDC loans (seeds [borrower, "loan"]) principal is USDC and collateral
+// is lamports, for SOL loans (seeds [borrower, "sol_loan"]) principal is
+// lamports and collateral is USDC. Fields may only be appended, so the
+// offsets below stay valid for getProgramAccounts memcmp filters and
+// dataSlice reads (e.g. all loans for a borrower: memcmp at
+// LOAN_BORROWER_OFFSET with the borrower pubkey). Loans opened before the
+// last three fields were added are LEGACY_LOAN_ACCOUNT_LEN bytes long; they
+// can be read and closed as they are, but must be grown with MigrateLoan
+// before a partial repayment or rollover can update them.
 #[derive(BorshSerialize, BorshDeserialize, Debug)]
 pub struct LoanAccount {
     pub borrower: Pubkey,
@@ -31,13 +101,310 @@ pub struct LoanAccount {
     pub principal: u64,
     pub apy: u64,
     pub collateral: u64,
+    pub interest_owed: u64,
+    pub fees_owed: u64,
+    pub last_accrual: i64,
+}
+
+pub const LOAN_BORROWER_OFFSET: usize = 0;
+pub const LOAN_START_DATE_OFFSET: usize = 32;
+pub const LOAN_PRINCIPAL_OFFSET: usize = 40;
+pub const LOAN_APY_OFFSET: usize = 48;
+pub const LOAN_COLLATERAL_OFFSET: usize = 56;
+pub const LOAN_INTEREST_OWED_OFFSET: usize = 64;
+pub const LOAN_FEES_OWED_OFFSET: usize = 72;
+pub const LOAN_LAST_ACCRUAL_OFFSET: usize = 80;
+pub const LOAN_ACCOUNT_LEN: usize = 88;
+pub const LEGACY_LOAN_ACCOUNT_LEN: usize = 64;
+
+// Loan account layout before interest_owed, fees_owed and last_accrual
+#[derive(BorshDeserialize)]
+struct LegacyLoanAccount {
+    borrower: Pubkey,
+    start_date: i64,
+    principal: u64,
+    apy: u64,
+    collateral: u64,
+}
+
+// Protocol-wide counters (seeds ["stats"]), updated by every loan instruction
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default)]
+pub struct Stats {
+    pub loans_originated: u64,
+    pub liquidations: u64,
+    pub usdc_borrowed: u64,
+    pub usdc_interest_paid: u64,
+    pub sol_collateral_locked: u64,
+    pub sol_borrowed: u64,
+    pub sol_interest_paid: u64,
+    pub usdc_collateral_locked: u64,
+    pub usdc_bad_debt_recorded: u64,
+    pub usdc_bad_debt_repaid: u64,
+    pub sol_bad_debt_recorded: u64,
+    pub sol_bad_debt_repaid: u64,
+}
+
+// Market a loan was taken out in
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Copy, PartialEq)]
+pub enum LoanMarket {
+    Usdc,
+    Sol,
+}
+
+// Summary of a closed loan, appended to the loan archive
+#[derive(BorshSerialize, BorshDeserialize, Debug, PartialEq)]
+pub struct ClosedLoan {
+    pub borrower: Pubkey,
+    pub market: LoanMarket,
+    pub liquidated: bool,
+    pub start_date: i64,
+    pub closed_at: i64,
+    pub collateral: u64,
+    pub final_payment: u64,
+}
+
+pub const CLOSED_LOAN_LEN: usize = 66;
+
+// The loan archive is split into LOAN_ARCHIVE_SHARDS accounts (seeds
+// ["loan_archive", shard]), a borrower's loans going to the shard of the first
+// byte of their pubkey, so closes of unrelated loans rarely contend for the
+// same account. Each shard is a u64 count of records ever written followed by
+// a ring buffer of LOAN_ARCHIVE_CAPACITY records; record i is at slot
+// i % LOAN_ARCHIVE_CAPACITY, so once full the oldest record is overwritten.
+// The archive is this program's audit trail; it does not use the per-user
+// audit log of the deposit programs.
+pub const LOAN_ARCHIVE_SHARDS: u8 = 16;
+pub const LOAN_ARCHIVE_CAPACITY: usize = 128;
+pub const LOAN_ARCHIVE_HEADER_LEN: usize = 8;
+pub const LOAN_ARCHIVE_LEN: usize = LOAN_ARCHIVE_HEADER_LEN + LOAN_ARCHIVE_CAPACITY * CLOSED_LOAN_LEN;
+
+// Debt left unpaid when a liquidation's collateral did not cover it (seeds
+// [borrower, "bad_debt"] for USDC loans, [borrower, "sol_bad_debt"] for SOL
+// loans). `amount` is in the loan's currency and shrinks as it is repaid;
+// `recorded_at` is the time of the latest shortfall. `funder` paid the
+// record's rent and gets it back when the record is closed.
+#[derive(BorshSerialize, BorshDeserialize, Debug, PartialEq)]
+pub struct BadDebt {
+    pub borrower: Pubkey,
+    pub market: LoanMarket,
+    pub amount: u64,
+    pub recorded_at: i64,
+    pub funder: Pubkey,
+}
+
+pub const BAD_DEBT_LEN: usize = 81;
+
+// Pending emergency sweep of program USDC to the treasury (seeds ["sweep"])
+#[derive(BorshSerialize, BorshDeserialize, Debug)]
+pub struct SweepProposal {
+    pub amount: u64,
+    pub executable_at: i64,
+}
+
+// Delegated hot key of a borrower (seeds [borrower, "session"]). Until
+// `expiry` it may repay the borrower's loans in either market, and nothing
+// else: it can never borrow, release collateral or change the session.
+#[derive(BorshSerialize, BorshDeserialize, Debug)]
+pub struct Session {
+    pub session_key: Pubkey,
+    pub expiry: i64,
+}
+
+// Standing order to repay `amount` USDC of a borrower's USDC loan every
+// `interval` seconds from `funding_account` (seeds [borrower,
+// "repayment_schedule"]). The schedule PDA must be an SPL token delegate of
+// the funding account; anyone may execute an installment once `next_due`
+// has passed.
+#[derive(BorshSerialize, BorshDeserialize, Debug, PartialEq)]
+pub struct RepaymentSchedule {
+    pub borrower: Pubkey,
+    pub funding_account: Pubkey,
+    pub amount: u64,
+    pub interval: i64,
+    pub next_due: i64,
+}
+
+// How a repayment was split across the loan's outstanding balances
+#[derive(Debug, Default, PartialEq)]
+pub struct RepaymentBreakdown {
+    pub fees_paid: u64,
+    pub interest_paid: u64,
+    pub principal_paid: u64,
+}
+
+impl RepaymentBreakdown {
+    pub fn total(&self) -> u64 {
+        self.fees_paid + self.interest_paid + self.principal_paid
+    }
+}
+
+// Return data of InitializeLoan and InitializeSolLoan, for CPI callers
+#[derive(BorshSerialize, BorshDeserialize, Debug, PartialEq)]
+pub struct BorrowResult {
+    pub principal: u64,
+    pub disbursed: u64,
+    pub collateral_locked: u64,
+    pub origination_fee: u64,
+}
+
+// What LiquidateLoan would do to a USDC loan at a given time, for bots and
+// UIs. `repay_amount` is the USDC the liquidator pays for `collateral_received`
+// lamports, and `expected_profit` the USDC value of the collateral above that.
+// The profit leaves out transaction fees, the loan account's rent the
+// liquidator also receives and the rent of a new bad debt record.
+#[derive(Debug, Default, PartialEq)]
+pub struct LiquidationPreview {
+    pub eligible: bool,
+    pub repay_amount: u64,
+    pub collateral_received: u64,
+    pub expected_profit: u64,
+}
+
+// Return data of the repay and liquidate instructions, for CPI callers.
+// `collateral_released` is zero unless the instruction closed the loan.
+#[derive(BorshSerialize, BorshDeserialize, Debug, PartialEq)]
+pub struct RepayResult {
+    pub fees_paid: u64,
+    pub interest_paid: u64,
+    pub principal_paid: u64,
+    pub collateral_released: u64,
 }
 
 #[derive(BorshSerialize, BorshDeserialize, Debug)]
 pub enum LoanInstruction {
-    InitializeLoan { amount: u64, apy: u64 },
-    RepayLoan { amount: u64 },
-    LiquidateLoan,
+    // `max_collateral` bounds the collateral the borrower agreed to lock.
+    // `deadline` is a unix timestamp after which the instruction is rejected.
+    InitializeLoan { amount: u64, apy: u64, max_collateral: Option<u64>, deadline: Option<i64> },
+    RepayLoan { amount: u64, deadline: Option<i64> },
+    LiquidateLoan { deadline: Option<i64> },
+    RolloverLoan,
+    InitializeSolReserve,
+    InitializeSolLoan { amount: u64, apy: u64, max_collateral: Option<u64>, deadline: Option<i64> },
+    RepaySolLoan { amount: u64, deadline: Option<i64> },
+    LiquidateSolLoan { deadline: Option<i64> },
+    BlockAddress { address: Pubkey },
+    UnblockAddress { address: Pubkey },
+    AnnounceEmergencySweep { amount: u64 },
+    ExecuteEmergencySweep,
+    CancelEmergencySweep,
+    InitializeStats,
+    InitializeLoanArchive { shard: u8 },
+    RegisterSession { session_key: Pubkey, expiry: i64 },
+    RevokeSession,
+    InitializeCollateralVault,
+    MigrateLoan,
+    RepayBadDebt { amount: u64 },
+    RepaySolBadDebt { amount: u64 },
+    CreateRepaymentSchedule { amount: u64, interval: i64, first_due: i64 },
+    CancelRepaymentSchedule,
+    ExecuteScheduledRepayment,
+}
+
+// Instruction builders for the USDC market, for clients and for programs
+// composing with this one over CPI. They fill in every PDA the handler checks;
+// the caller passes its own token accounts. `credential_account` is the
+// borrower's credential token account and is only sent when
+// REQUIRED_CREDENTIAL_MINT is set.
+pub fn initialize_loan_instruction(
+    program_id: &Pubkey,
+    borrower: &Pubkey,
+    borrower_usdc_account: &Pubkey,
+    credential_account: Option<&Pubkey>,
+    amount: u64,
+    apy: u64,
+    max_collateral: Option<u64>,
+    deadline: Option<i64>,
+) -> Instruction {
+    let (loan_account, _) = Pubkey::find_program_address(&[borrower.as_ref(), b"loan"], program_id);
+    let (authority, _) = Pubkey::find_program_address(&[b"authority"], program_id);
+    let (blocklist_marker, _) = blocklist::marker_address(program_id, borrower);
+    let (stats, _) = Pubkey::find_program_address(&[b"stats"], program_id);
+    let (bad_debt, _) = Pubkey::find_program_address(&[borrower.as_ref(), bad_debt_seed(LoanMarket::Usdc)], program_id);
+
+    let mut accounts = vec![
+        AccountMeta::new(*borrower, true),
+        AccountMeta::new(loan_account, false),
+        AccountMeta::new(*borrower_usdc_account, false),
+        AccountMeta::new(PROGRAM_USDC_ACCOUNT, false),
+        AccountMeta::new(TREASURY_USDC_ACCOUNT, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+        AccountMeta::new_readonly(authority, false),
+        AccountMeta::new_readonly(solana_program::sysvar::rent::id(), false),
+        AccountMeta::new_readonly(solana_program::sysvar::clock::id(), false),
+        AccountMeta::new_readonly(blocklist_marker, false),
+        AccountMeta::new(stats, false),
+        AccountMeta::new_readonly(bad_debt, false),
+    ];
+    if let (Some(_), Some(credential_account)) = (REQUIRED_CREDENTIAL_MINT, credential_account) {
+        accounts.push(AccountMeta::new_readonly(*credential_account, false));
+    }
+    if CPI_ALLOWLIST.is_some() {
+        accounts.push(AccountMeta::new_readonly(solana_program::sysvar::instructions::id(), false));
+    }
+
+    Instruction::new_with_borsh(
+        *program_id,
+        &LoanInstruction::InitializeLoan { amount, apy, max_collateral, deadline },
+        accounts,
+    )
+}
+
+pub fn repay_loan_instruction(
+    program_id: &Pubkey,
+    borrower: &Pubkey,
+    borrower_usdc_account: &Pubkey,
+    amount: u64,
+    deadline: Option<i64>,
+) -> Instruction {
+    let (loan_account, _) = Pubkey::find_program_address(&[borrower.as_ref(), b"loan"], program_id);
+    let (stats, _) = Pubkey::find_program_address(&[b"stats"], program_id);
+    let (loan_archive, _) = Pubkey::find_program_address(&[b"loan_archive", &[loan_archive_shard(borrower)]], program_id);
+
+    Instruction::new_with_borsh(
+        *program_id,
+        &LoanInstruction::RepayLoan { amount, deadline },
+        vec![
+            AccountMeta::new(*borrower, true),
+            AccountMeta::new(loan_account, false),
+            AccountMeta::new(*borrower_usdc_account, false),
+            AccountMeta::new(PROGRAM_USDC_ACCOUNT, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(solana_program::sysvar::clock::id(), false),
+            AccountMeta::new(stats, false),
+            AccountMeta::new(loan_archive, false),
+        ],
+    )
+}
+
+pub fn liquidate_loan_instruction(
+    program_id: &Pubkey,
+    liquidator: &Pubkey,
+    liquidator_usdc_account: &Pubkey,
+    borrower: &Pubkey,
+    deadline: Option<i64>,
+) -> Instruction {
+    let (loan_account, _) = Pubkey::find_program_address(&[borrower.as_ref(), b"loan"], program_id);
+    let (stats, _) = Pubkey::find_program_address(&[b"stats"], program_id);
+    let (loan_archive, _) = Pubkey::find_program_address(&[b"loan_archive", &[loan_archive_shard(borrower)]], program_id);
+    let (bad_debt, _) = Pubkey::find_program_address(&[borrower.as_ref(), bad_debt_seed(LoanMarket::Usdc)], program_id);
+
+    Instruction::new_with_borsh(
+        *program_id,
+        &LoanInstruction::LiquidateLoan { deadline },
+        vec![
+            AccountMeta::new(*liquidator, true),
+            AccountMeta::new(loan_account, false),
+            AccountMeta::new(*liquidator_usdc_account, false),
+            AccountMeta::new(PROGRAM_USDC_ACCOUNT, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(solana_program::sysvar::clock::id(), false),
+            AccountMeta::new(stats, false),
+            AccountMeta::new(loan_archive, false),
+            AccountMeta::new(bad_debt, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+    )
 }
 
 #[derive(Error, Debug)]
@@ -62,15 +429,61 @@ pub enum LoanError {
 
     #[error("Loan is not underwater")]
     LoanNotUnderwater,
+
+    #[error("Loan amount is below the minimum")]
+    BelowMinimumLoan,
+
+    #[error("Calling program is not allowed to invoke this instruction")]
+    UnauthorizedCpiCaller,
+
+    #[error("Insufficient SOL liquidity")]
+    InsufficientLiquidity,
+
+    #[error("Signer is not the program admin")]
+    Unauthorized,
+
+    #[error("Address is blocked")]
+    AddressBlocked,
+
+    #[error("Borrower does not hold the required credential")]
+    MissingCredential,
+
+    #[error("Emergency sweep delay has not elapsed")]
+    SweepNotReady,
+
+    #[error("Token account has the wrong mint or owner")]
+    InvalidTokenAccount,
+
+    #[error("Required collateral exceeds the borrower's maximum")]
+    CollateralAboveMaximum,
+
+    #[error("Transaction deadline has passed")]
+    DeadlinePassed,
+
+    #[error("Session key is not registered or has expired")]
+    InvalidSessionKey,
+
+    #[error("Loan account uses the legacy layout and must be migrated")]
+    LoanNeedsMigration,
+
+    #[error("Scheduled repayment is not due yet")]
+    RepaymentNotDue,
+
+    #[error("Borrower has outstanding bad debt")]
+    OutstandingBadDebt,
 }
 
+// LoanError codes start at 3000 (see Error Codes in the README)
+const ERROR_CODE_BASE: u32 = 3000;
+
 impl From<LoanError> for ProgramError {
     fn from(e: LoanError) -> Self {
-        ProgramError::Custom(e as u32)
+        ProgramError::Custom(ERROR_CODE_BASE + e as u32)
     }
 }
 
-entrypoint!(process_instruction);
+#[cfg(not(feature = "no-entrypoint"))]
+solana_program::entrypoint!(process_instruction);
 
 pub fn process_instruction(
     program_id: &Pubkey,
@@ -81,214 +494,2109 @@ pub fn process_instruction(
         .map_err(|_| LoanError::InvalidInstruction)?;
 
     match instruction {
-        LoanInstruction::InitializeLoan { amount, apy } => {
-            initialize_loan(program_id, accounts, amount, apy)
+        LoanInstruction::InitializeLoan { amount, apy, max_collateral, deadline } => {
+            initialize_loan(program_id, accounts, amount, apy, max_collateral, deadline)
+        }
+        LoanInstruction::RepayLoan { amount, deadline } => repay_loan(program_id, accounts, amount, deadline),
+        LoanInstruction::LiquidateLoan { deadline } => liquidate_loan(program_id, accounts, deadline),
+        LoanInstruction::RolloverLoan => rollover_loan(program_id, accounts),
+        LoanInstruction::InitializeSolReserve => initialize_sol_reserve(program_id, accounts),
+        LoanInstruction::InitializeSolLoan { amount, apy, max_collateral, deadline } => {
+            initialize_sol_loan(program_id, accounts, amount, apy, max_collateral, deadline)
         }
-        LoanInstruction::RepayLoan { amount } => repay_loan(accounts, amount),
-        LoanInstruction::LiquidateLoan => liquidate_loan(accounts),
+        LoanInstruction::RepaySolLoan { amount, deadline } => repay_sol_loan(program_id, accounts, amount, deadline),
+        LoanInstruction::LiquidateSolLoan { deadline } => liquidate_sol_loan(program_id, accounts, deadline),
+        LoanInstruction::BlockAddress { address } => block_address(program_id, accounts, address),
+        LoanInstruction::UnblockAddress { address } => unblock_address(program_id, accounts, address),
+        LoanInstruction::AnnounceEmergencySweep { amount } => {
+            announce_emergency_sweep(program_id, accounts, amount)
+        }
+        LoanInstruction::ExecuteEmergencySweep => execute_emergency_sweep(program_id, accounts),
+        LoanInstruction::CancelEmergencySweep => cancel_emergency_sweep(program_id, accounts),
+        LoanInstruction::InitializeStats => initialize_stats(program_id, accounts),
+        LoanInstruction::InitializeLoanArchive { shard } => initialize_loan_archive(program_id, accounts, shard),
+        LoanInstruction::RegisterSession { session_key, expiry } => {
+            register_session(program_id, accounts, session_key, expiry)
+        }
+        LoanInstruction::RevokeSession => revoke_session(program_id, accounts),
+        LoanInstruction::InitializeCollateralVault => initialize_collateral_vault(program_id, accounts),
+        LoanInstruction::MigrateLoan => migrate_loan(program_id, accounts),
+        LoanInstruction::RepayBadDebt { amount } => repay_bad_debt(program_id, accounts, amount),
+        LoanInstruction::RepaySolBadDebt { amount } => repay_sol_bad_debt(program_id, accounts, amount),
+        LoanInstruction::CreateRepaymentSchedule { amount, interval, first_due } => {
+            create_repayment_schedule(program_id, accounts, amount, interval, first_due)
+        }
+        LoanInstruction::CancelRepaymentSchedule => cancel_repayment_schedule(program_id, accounts),
+        LoanInstruction::ExecuteScheduledRepayment => execute_scheduled_repayment(program_id, accounts),
     }
 }
 
-fn initialize_loan(
+// Collateral (in lamports) required to borrow `amount` USDC at the program LTV.
+// Rounded up so a loan is never under-collateralized by a rounding error.
+pub fn required_collateral(amount: u64) -> Result<u64, ProgramError> {
+    collateral_for(amount, USDC_DECIMALS, SOL_PRICE, LTV)
+}
+
+// Collateral (in lamports) required to borrow `amount` of a token with
+// `decimals` decimals, at a SOL price of `price` dollars and `ltv` percent
+fn collateral_for(amount: u64, decimals: u8, price: u64, ltv: u64) -> Result<u64, ProgramError> {
+    let value = to_canonical(amount, decimals).ok_or(LoanError::Overflow)?;
+    let collateral = price
+        .checked_mul(ltv)
+        .and_then(|denominator| mul_div(value, 100, denominator, Rounding::Up))
+        .and_then(|c| from_canonical(c, SOL_DECIMALS, Rounding::Up))
+        .ok_or(LoanError::Overflow)?;
+    Ok(collateral)
+}
+
+// USDC value of `collateral` lamports at the program SOL price, rounded down
+fn collateral_value(collateral: u64) -> Result<u64, ProgramError> {
+    let collateral = to_canonical(collateral, SOL_DECIMALS).ok_or(LoanError::Overflow)?;
+    let value = mul_div(collateral, SOL_PRICE, 1, Rounding::Down)
+        .and_then(|v| from_canonical(v, USDC_DECIMALS, Rounding::Down))
+        .ok_or(LoanError::Overflow)?;
+    Ok(value)
+}
+
+// USDC collateral required to borrow `amount` lamports at the SOL loan LTV, rounded up
+fn required_usdc_collateral(amount: u64) -> Result<u64, ProgramError> {
+    let amount = to_canonical(amount, SOL_DECIMALS).ok_or(LoanError::Overflow)?;
+    let collateral = mul_div(amount, SOL_PRICE * 100, SOL_LOAN_LTV, Rounding::Up)
+        .and_then(|c| from_canonical(c, USDC_DECIMALS, Rounding::Up))
+        .ok_or(LoanError::Overflow)?;
+    Ok(collateral)
+}
+
+// USDC value of a SOL debt of `debt` lamports, rounded up
+fn sol_debt_value(debt: u64) -> Result<u64, ProgramError> {
+    let debt = to_canonical(debt, SOL_DECIMALS).ok_or(LoanError::Overflow)?;
+    let value = mul_div(debt, SOL_PRICE, 1, Rounding::Up)
+        .and_then(|v| from_canonical(v, USDC_DECIMALS, Rounding::Up))
+        .ok_or(LoanError::Overflow)?;
+    Ok(value)
+}
+
+// Lamports a liquidator pays for `collateral` USDC at the program SOL price, rounded up
+fn usdc_collateral_sol_value(collateral: u64) -> Result<u64, ProgramError> {
+    let collateral = to_canonical(collateral, USDC_DECIMALS).ok_or(LoanError::Overflow)?;
+    let value = mul_div(collateral, 1, SOL_PRICE, Rounding::Up)
+        .and_then(|v| from_canonical(v, SOL_DECIMALS, Rounding::Up))
+        .ok_or(LoanError::Overflow)?;
+    Ok(value)
+}
+
+// Simple interest accrued on the principal since the last accrual, rounded up
+fn accrued_interest(loan_data: &LoanAccount, now: i64) -> Result<u64, ProgramError> {
+    let time_elapsed = (now - loan_data.last_accrual) as u64;
+    let rate_time = loan_data.apy.checked_mul(time_elapsed).ok_or(LoanError::Overflow)?;
+    let interest = mul_div(loan_data.principal, rate_time, SECONDS_PER_YEAR * 100, Rounding::Up)
+        .ok_or(LoanError::Overflow)?;
+    Ok(interest)
+}
+
+// Whether `credential_account` is a token account owned by `borrower` holding
+// at least one `mint` token. Frozen accounts count, as credentials are
+// usually frozen to keep them non-transferable.
+fn holds_credential(borrower: &Pubkey, credential_account: &AccountInfo, mint: &Pubkey) -> Result<bool, ProgramError> {
+    if *credential_account.owner != spl_token::id() {
+        return Ok(false);
+    }
+
+    let credential = TokenAccount::unpack(&credential_account.data.borrow())?;
+    Ok(credential.mint == *mint && credential.owner == *borrower && credential.amount > 0)
+}
+
+fn set_repay_return_data(breakdown: &RepaymentBreakdown, collateral_released: u64) -> ProgramResult {
+    let result = RepayResult {
+        fees_paid: breakdown.fees_paid,
+        interest_paid: breakdown.interest_paid,
+        principal_paid: breakdown.principal_paid,
+        collateral_released,
+    };
+    set_return_data(&result.try_to_vec()?);
+    Ok(())
+}
+
+// Fails once the caller's deadline, if any, has passed
+fn check_deadline(deadline: Option<i64>, now: i64) -> ProgramResult {
+    match deadline {
+        Some(deadline) if now > deadline => {
+            msg!("Deadline passed: deadline {}, now {}", deadline, now);
+            Err(LoanError::DeadlinePassed.into())
+        }
+        _ => Ok(()),
+    }
+}
+
+// Fails unless `key` is the session's key and the session has not expired
+fn check_session(session: &Session, key: &Pubkey, now: i64) -> ProgramResult {
+    if session.session_key != *key {
+        return Err(LoanError::InvalidSessionKey.into());
+    }
+
+    if now > session.expiry {
+        msg!("Session expired at {}, now {}", session.expiry, now);
+        return Err(LoanError::InvalidSessionKey.into());
+    }
+
+    Ok(())
+}
+
+// Returns the account authorizing a repayment: the borrower if they signed,
+// otherwise a session key of theirs, passed after their session PDA as the
+// next two accounts. A session key repaying USDC must be an SPL token
+// delegate of the borrower's USDC account.
+fn repay_authority<'a, 'b>(
     program_id: &Pubkey,
-    accounts: &[AccountInfo],
+    borrower: &'b AccountInfo<'a>,
+    account_info_iter: &mut std::slice::Iter<'b, AccountInfo<'a>>,
+    now: i64,
+) -> Result<&'b AccountInfo<'a>, ProgramError> {
+    if borrower.is_signer {
+        return Ok(borrower);
+    }
+
+    let session_account = next_account_info(account_info_iter).map_err(|_| ProgramError::MissingRequiredSignature)?;
+    let session_key = next_account_info(account_info_iter).map_err(|_| ProgramError::MissingRequiredSignature)?;
+    if !session_key.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let (pda, _) = Pubkey::find_program_address(&[borrower.key.as_ref(), b"session"], program_id);
+    if pda != *session_account.key || session_account.owner != program_id {
+        return Err(LoanError::InvalidSessionKey.into());
+    }
+
+    let session = Session::try_from_slice(&session_account.data.borrow())?;
+    check_session(&session, session_key.key, now)?;
+    Ok(session_key)
+}
+
+// Fails if origination would lock more collateral than the borrower signed for
+fn check_max_collateral(required_collateral: u64, max_collateral: Option<u64>) -> ProgramResult {
+    match max_collateral {
+        Some(max) if required_collateral > max => {
+            msg!("Collateral above maximum: need {}, borrower allowed {}", required_collateral, max);
+            Err(LoanError::CollateralAboveMaximum.into())
+        }
+        _ => Ok(()),
+    }
+}
+
+// Checks that `token_account` is a USDC token account owned by `owner`, so
+// callers cannot pay or be paid through an account of another mint
+fn check_usdc_account(token_account: &AccountInfo, owner: &Pubkey) -> ProgramResult {
+    if *token_account.owner != spl_token::id() {
+        return Err(LoanError::InvalidTokenAccount.into());
+    }
+
+    let account = TokenAccount::unpack(&token_account.data.borrow())?;
+    if account.mint != USDC_MINT || account.owner != *owner {
+        return Err(LoanError::InvalidTokenAccount.into());
+    }
+    Ok(())
+}
+
+// Deserializes the stats PDA after checking its address
+fn load_stats(program_id: &Pubkey, stats_account: &AccountInfo) -> Result<Stats, ProgramError> {
+    let (pda, _) = Pubkey::find_program_address(&[b"stats"], program_id);
+    if pda != *stats_account.key {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let stats = Stats::try_from_slice(&stats_account.data.borrow())?;
+    Ok(stats)
+}
+
+// Parses loan account data in the current or the legacy layout. A legacy
+// loan owes no separately tracked interest or fees yet, and accrues interest
+// from its start date.
+fn parse_loan_account(data: &[u8]) -> Result<LoanAccount, ProgramError> {
+    if data.len() != LEGACY_LOAN_ACCOUNT_LEN {
+        return Ok(LoanAccount::try_from_slice(data)?);
+    }
+
+    let legacy = LegacyLoanAccount::try_from_slice(data)?;
+    Ok(LoanAccount {
+        borrower: legacy.borrower,
+        start_date: legacy.start_date,
+        principal: legacy.principal,
+        apy: legacy.apy,
+        collateral: legacy.collateral,
+        interest_owed: 0,
+        fees_owed: 0,
+        last_accrual: legacy.start_date,
+    })
+}
+
+fn load_loan_account(loan_account: &AccountInfo) -> Result<LoanAccount, ProgramError> {
+    parse_loan_account(&loan_account.data.borrow())
+}
+
+// Writes back an open loan. Legacy accounts are too short for the current
+// layout and have to go through MigrateLoan first.
+fn store_loan_account(loan_account: &AccountInfo, loan_data: &LoanAccount) -> ProgramResult {
+    if loan_account.data_len() < LOAN_ACCOUNT_LEN {
+        msg!("Loan account is {} bytes, migrate it to {} bytes first", loan_account.data_len(), LOAN_ACCOUNT_LEN);
+        return Err(LoanError::LoanNeedsMigration.into());
+    }
+
+    loan_data.serialize(&mut &mut loan_account.data.borrow_mut()[..])?;
+    Ok(())
+}
+
+fn bad_debt_seed(market: LoanMarket) -> &'static [u8] {
+    match market {
+        LoanMarket::Usdc => b"bad_debt",
+        LoanMarket::Sol => b"sol_bad_debt",
+    }
+}
+
+// Adds `shortfall` to the borrower's bad debt in `market`. The liquidator
+// pays the rent of the record the first time one is needed.
+#[allow(clippy::too_many_arguments)]
+fn record_bad_debt<'a>(
+    program_id: &Pubkey,
+    liquidator: &AccountInfo<'a>,
+    bad_debt_account: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    borrower: &Pubkey,
+    market: LoanMarket,
+    shortfall: u64,
+    now: i64,
+) -> ProgramResult {
+    let (pda, bump_seed) = Pubkey::find_program_address(&[borrower.as_ref(), bad_debt_seed(market)], program_id);
+    if pda != *bad_debt_account.key {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    if shortfall == 0 {
+        return Ok(());
+    }
+
+    let mut bad_debt = if bad_debt_account.owner == program_id {
+        BadDebt::try_from_slice(&bad_debt_account.data.borrow())?
+    } else {
+        invoke_signed(
+            &system_instruction::create_account(
+                liquidator.key,
+                bad_debt_account.key,
+                Rent::get()?.minimum_balance(BAD_DEBT_LEN),
+                BAD_DEBT_LEN as u64,
+                program_id,
+            ),
+            &[liquidator.clone(), bad_debt_account.clone(), system_program.clone()],
+            &[&[borrower.as_ref(), bad_debt_seed(market), &[bump_seed]]],
+        )?;
+        BadDebt { borrower: *borrower, market, amount: 0, recorded_at: now, funder: *liquidator.key }
+    };
+
+    bad_debt.amount = bad_debt.amount.checked_add(shortfall).ok_or(LoanError::Overflow)?;
+    bad_debt.recorded_at = now;
+    bad_debt.serialize(&mut &mut bad_debt_account.data.borrow_mut()[..])?;
+
+    msg!("Bad debt recorded for {}: {} unrecovered, {} outstanding", borrower, shortfall, bad_debt.amount);
+    Ok(())
+}
+
+// Applies a bad debt repayment of up to `amount`, returning the amount to
+// collect. Closes the record, returning its rent to `funder`, once it is paid
+// off.
+fn settle_bad_debt(
+    program_id: &Pubkey,
+    funder: &AccountInfo,
+    bad_debt_account: &AccountInfo,
+    market: LoanMarket,
+    amount: u64,
+) -> Result<u64, ProgramError> {
+    if bad_debt_account.owner != program_id {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let mut bad_debt = BadDebt::try_from_slice(&bad_debt_account.data.borrow())?;
+    let (pda, _) = Pubkey::find_program_address(&[bad_debt.borrower.as_ref(), bad_debt_seed(market)], program_id);
+    if pda != *bad_debt_account.key || bad_debt.market != market || bad_debt.funder != *funder.key {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    if amount == 0 {
+        return Err(LoanError::InsufficientRepaymentAmount.into());
+    }
+
+    let paid = amount.min(bad_debt.amount);
+    bad_debt.amount -= paid;
+
+    if bad_debt.amount > 0 {
+        bad_debt.serialize(&mut &mut bad_debt_account.data.borrow_mut()[..])?;
+        return Ok(paid);
+    }
+
+    **funder.try_borrow_mut_lamports()? = funder.lamports()
+        .checked_add(bad_debt_account.lamports())
+        .ok_or(LoanError::Overflow)?;
+    **bad_debt_account.try_borrow_mut_lamports()? = 0;
+
+    bad_debt_account.realloc(0, false)?;
+    bad_debt_account.assign(&system_program::id());
+    Ok(paid)
+}
+
+// Fails if `borrower` still owes bad debt in `market`. Paid off records are
+// closed, so any record the program owns is outstanding.
+fn check_no_bad_debt(program_id: &Pubkey, bad_debt_account: &AccountInfo, borrower: &Pubkey, market: LoanMarket) -> ProgramResult {
+    let (pda, _) = Pubkey::find_program_address(&[borrower.as_ref(), bad_debt_seed(market)], program_id);
+    if pda != *bad_debt_account.key {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    if bad_debt_account.owner == program_id {
+        let bad_debt = BadDebt::try_from_slice(&bad_debt_account.data.borrow())?;
+        msg!("Borrower {} has {} of bad debt outstanding", borrower, bad_debt.amount);
+        return Err(LoanError::OutstandingBadDebt.into());
+    }
+    Ok(())
+}
+
+// Value a liquidator pays for collateral worth `value`, after the liquidation bonus
+fn liquidation_payment(value: u64) -> Result<u64, ProgramError> {
+    let payment = mul_div(value, BPS_DENOMINATOR - LIQUIDATION_BONUS_BPS, BPS_DENOMINATOR, Rounding::Down)
+        .ok_or(LoanError::Overflow)?;
+    Ok(payment)
+}
+
+// Previews liquidating the USDC loan whose account data is `loan_account_data`
+// (either layout) at unix time `now`, with the same math as LiquidateLoan
+pub fn liquidation_preview(loan_account_data: &[u8], now: i64) -> Result<LiquidationPreview, ProgramError> {
+    let mut loan_data = parse_loan_account(loan_account_data)?;
+    accrue(&mut loan_data, now)?;
+    let total_due = total_due(&loan_data)?;
+
+    let value = collateral_value(loan_data.collateral)?;
+    if value >= total_due {
+        return Ok(LiquidationPreview::default());
+    }
+
+    let repay_amount = apply_repayment(&mut loan_data, liquidation_payment(value)?).total();
+    Ok(LiquidationPreview {
+        eligible: true,
+        repay_amount,
+        collateral_received: loan_data.collateral,
+        expected_profit: value - repay_amount,
+    })
+}
+
+// Checks that `vault` is the USDC collateral vault of the SOL market
+fn check_collateral_vault(program_id: &Pubkey, vault: &AccountInfo) -> ProgramResult {
+    let (pda, _) = Pubkey::find_program_address(&[b"usdc_collateral_vault"], program_id);
+    if pda != *vault.key {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    Ok(())
+}
+
+// Transfers `amount` USDC out of one of the program's token accounts, signed
+// by the authority PDA (seeds ["authority"]) that owns them
+fn transfer_from_program<'a>(
+    program_id: &Pubkey,
+    token_program: &AccountInfo<'a>,
+    source: &AccountInfo<'a>,
+    destination: &AccountInfo<'a>,
+    authority: &AccountInfo<'a>,
     amount: u64,
-    apy: u64,
 ) -> ProgramResult {
-    let account_info_iter = &mut accounts.iter();
-    let borrower = next_account_info(account_info_iter)?;
-    let loan_account = next_account_info(account_info_iter)?;
-    let borrower_usdc_account = next_account_info(account_info_iter)?;
-    let program_usdc_account = next_account_info(account_info_iter)?;
-    let system_program = next_account_info(account_info_iter)?;
-    let token_program = next_account_info(account_info_iter)?;
+    let (pda, bump_seed) = Pubkey::find_program_address(&[b"authority"], program_id);
+    if pda != *authority.key {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    invoke_signed(
+        &token_instruction::transfer(token_program.key, source.key, destination.key, authority.key, &[], amount)?,
+        &[source.clone(), destination.clone(), authority.clone(), token_program.clone()],
+        &[&[b"authority", &[bump_seed]]],
+    )
+}
+
+// Archive shard holding the closed loans of `borrower`
+fn loan_archive_shard(borrower: &Pubkey) -> u8 {
+    borrower.to_bytes()[0] % LOAN_ARCHIVE_SHARDS
+}
+
+// Records `record` in its borrower's archive shard. Archiving never blocks a
+// close: if the shard has not been initialized the record is only logged.
+fn archive_closed_loan(program_id: &Pubkey, loan_archive: &AccountInfo, record: &ClosedLoan) -> ProgramResult {
+    let shard = loan_archive_shard(&record.borrower);
+    let (pda, _) = Pubkey::find_program_address(&[b"loan_archive", &[shard]], program_id);
+    if pda != *loan_archive.key {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    if loan_archive.owner != program_id {
+        msg!("Loan archive shard {} is not initialized, closed loan not archived", shard);
+        return Ok(());
+    }
+
+    push_closed_loan(&mut loan_archive.data.borrow_mut(), record)
+}
+
+// Writes `record` over the oldest slot of an archive shard's ring buffer
+fn push_closed_loan(data: &mut [u8], record: &ClosedLoan) -> ProgramResult {
+    let count = u64::try_from_slice(&data[..LOAN_ARCHIVE_HEADER_LEN])?;
+
+    let start = LOAN_ARCHIVE_HEADER_LEN + (count % LOAN_ARCHIVE_CAPACITY as u64) as usize * CLOSED_LOAN_LEN;
+    record.serialize(&mut &mut data[start..start + CLOSED_LOAN_LEN])?;
+
+    let count = count.checked_add(1).ok_or(LoanError::Overflow)?;
+    count.serialize(&mut &mut data[..LOAN_ARCHIVE_HEADER_LEN])?;
+    Ok(())
+}
+
+// Splits a requested loan amount into principal, disbursement and origination fee
+fn loan_terms(amount: u64, mode: OriginationFeeMode) -> Result<LoanTerms, ProgramError> {
+    let origination_fee = mul_div(amount, ORIGINATION_FEE_BPS, BPS_DENOMINATOR, Rounding::Up)
+        .ok_or(LoanError::Overflow)?;
+
+    let terms = match mode {
+        OriginationFeeMode::DeductFromDisbursement => LoanTerms {
+            principal: amount,
+            disbursed: amount - origination_fee,
+            origination_fee,
+        },
+        OriginationFeeMode::AddToPrincipal => LoanTerms {
+            principal: amount.checked_add(origination_fee).ok_or(LoanError::Overflow)?,
+            disbursed: amount,
+            origination_fee,
+        },
+    };
+    Ok(terms)
+}
+
+// Fee charged for repaying principal early, based on the loan age at `now`.
+// The fee comes out of `amount`, so the principal it is charged on is the part
+// of the payment left after fees, interest and the fee itself.
+fn prepayment_fee(loan_data: &LoanAccount, amount: u64, now: i64, schedule: &[(i64, u64)]) -> Result<u64, ProgramError> {
+    let loan_age = now - loan_data.start_date;
+    let fee_bps = match schedule.iter().find(|(max_age, _)| loan_age < *max_age) {
+        Some((_, fee_bps)) => *fee_bps,
+        None => return Ok(0),
+    };
+
+    let available = amount
+        .saturating_sub(loan_data.fees_owed)
+        .saturating_sub(loan_data.interest_owed);
+    let principal_repaid = mul_div(available, BPS_DENOMINATOR, BPS_DENOMINATOR + fee_bps, Rounding::Down)
+        .ok_or(LoanError::Overflow)?
+        .min(loan_data.principal);
+
+    let fee = mul_div(principal_repaid, fee_bps, BPS_DENOMINATOR, Rounding::Up).ok_or(LoanError::Overflow)?;
+    Ok(fee)
+}
+
+// Accrues interest, charges the prepayment fee `schedule` sets for early
+// repayment of principal and applies `amount` to fees, then interest, then
+// principal. Used by the repay instructions of both markets.
+fn apply_loan_repayment(
+    loan_data: &mut LoanAccount,
+    amount: u64,
+    now: i64,
+    schedule: &[(i64, u64)],
+) -> Result<RepaymentBreakdown, ProgramError> {
+    accrue(loan_data, now)?;
+
+    let prepayment_fee = prepayment_fee(loan_data, amount, now, schedule)?;
+    loan_data.fees_owed = loan_data.fees_owed.checked_add(prepayment_fee).ok_or(LoanError::Overflow)?;
+
+    Ok(apply_repayment(loan_data, amount))
+}
+
+// Clears a residual debt smaller than `threshold` so the loan can be closed.
+// Returns the amount forgiven.
+fn forgive_dust(loan_data: &mut LoanAccount, threshold: u64) -> Result<u64, ProgramError> {
+    let remaining = total_due(loan_data)?;
+    if remaining == 0 || remaining >= threshold {
+        return Ok(0);
+    }
+
+    loan_data.fees_owed = 0;
+    loan_data.interest_owed = 0;
+    loan_data.principal = 0;
+    Ok(remaining)
+}
+
+// Moves interest accrued since the last accrual into `interest_owed`
+fn accrue(loan_data: &mut LoanAccount, now: i64) -> ProgramResult {
+    let interest = accrued_interest(loan_data, now)?;
+    loan_data.interest_owed = loan_data.interest_owed.checked_add(interest).ok_or(LoanError::Overflow)?;
+    loan_data.last_accrual = now;
+    Ok(())
+}
+
+// Fees, accrued interest and principal outstanding on an accrued loan
+fn total_due(loan_data: &LoanAccount) -> Result<u64, ProgramError> {
+    let total = loan_data.principal
+        .checked_add(loan_data.interest_owed)
+        .and_then(|v| v.checked_add(loan_data.fees_owed))
+        .ok_or(LoanError::Overflow)?;
+    Ok(total)
+}
+
+// Repayment waterfall. `amount` is applied to outstanding fees first, then to
+// accrued interest, then to principal. Anything beyond the total due is left
+// unapplied, so callers should only collect `RepaymentBreakdown::total()`.
+// The loan must be accrued up to the current time before calling this.
+fn apply_repayment(loan_data: &mut LoanAccount, amount: u64) -> RepaymentBreakdown {
+    let mut remaining = amount;
+
+    let fees_paid = remaining.min(loan_data.fees_owed);
+    loan_data.fees_owed -= fees_paid;
+    remaining -= fees_paid;
+
+    let interest_paid = remaining.min(loan_data.interest_owed);
+    loan_data.interest_owed -= interest_paid;
+    remaining -= interest_paid;
+
+    let principal_paid = remaining.min(loan_data.principal);
+    loan_data.principal -= principal_paid;
+
+    RepaymentBreakdown {
+        fees_paid,
+        interest_paid,
+        principal_paid,
+    }
+}
+
+fn initialize_loan(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    amount: u64,
+    apy: u64,
+    max_collateral: Option<u64>,
+    deadline: Option<i64>,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let borrower = next_account_info(account_info_iter)?;
+    let loan_account = next_account_info(account_info_iter)?;
+    let borrower_usdc_account = next_account_info(account_info_iter)?;
+    let program_usdc_account = next_account_info(account_info_iter)?;
+    let treasury_usdc_account = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+    let token_program = next_account_info(account_info_iter)?;
+    let program_authority = next_account_info(account_info_iter)?;
+    let rent = &Rent::from_account_info(next_account_info(account_info_iter)?)?;
+    let clock = &Clock::from_account_info(next_account_info(account_info_iter)?)?;
+    let blocklist_marker = next_account_info(account_info_iter)?;
+    let stats_account = next_account_info(account_info_iter)?;
+    let bad_debt_account = next_account_info(account_info_iter)?;
+
+    if let Some(credential_mint) = REQUIRED_CREDENTIAL_MINT {
+        let credential_account = next_account_info(account_info_iter)?;
+        if !holds_credential(borrower.key, credential_account, &credential_mint)? {
+            return Err(LoanError::MissingCredential.into());
+        }
+    }
+
+    if let Some(allowlist) = CPI_ALLOWLIST {
+        let instructions_sysvar = next_account_info(account_info_iter)?;
+        if !cpi_guard::is_allowed_caller(program_id, instructions_sysvar, allowlist)? {
+            return Err(LoanError::UnauthorizedCpiCaller.into());
+        }
+    }
+
+    if !borrower.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    check_deadline(deadline, clock.unix_timestamp)?;
+
+    check_usdc_account(borrower_usdc_account, borrower.key)?;
+    if *program_usdc_account.key != PROGRAM_USDC_ACCOUNT || *token_program.key != spl_token::id() {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    if blocklist::is_blocked(program_id, borrower.key, blocklist_marker)? {
+        return Err(LoanError::AddressBlocked.into());
+    }
+
+    check_no_bad_debt(program_id, bad_debt_account, borrower.key, LoanMarket::Usdc)?;
+
+    if amount == 0 {
+        return Err(LoanError::InvalidLoanAmount.into());
+    }
+
+    if amount < MIN_LOAN_PRINCIPAL {
+        msg!("Loan amount below minimum: requested {} USDC, minimum {} USDC", amount, MIN_LOAN_PRINCIPAL);
+        return Err(LoanError::BelowMinimumLoan.into());
+    }
+
+    if *treasury_usdc_account.key != TREASURY_USDC_ACCOUNT {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let terms = loan_terms(amount, ORIGINATION_FEE_MODE)?;
+
+    // Calculate required collateral
+    let required_collateral = required_collateral(terms.principal)?;
+    check_max_collateral(required_collateral, max_collateral)?;
+    if borrower.lamports() < required_collateral {
+        msg!(
+            "Insufficient collateral: need {} lamports, borrower has {} lamports",
+            required_collateral,
+            borrower.lamports()
+        );
+        return Err(LoanError::InsufficientCollateral.into());
+    }
+
+    // Create loan account
+    let (pda, bump_seed) = Pubkey::find_program_address(&[borrower.key.as_ref(), b"loan"], program_id);
+    if pda != *loan_account.key {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let space = std::mem::size_of::<LoanAccount>();
+    let rent_lamports = rent.minimum_balance(space);
+
+    invoke_signed(
+        &system_instruction::create_account(
+            borrower.key,
+            loan_account.key,
+            rent_lamports,
+            space as u64,
+            program_id,
+        ),
+        &[borrower.clone(), loan_account.clone(), system_program.clone()],
+        &[&[borrower.key.as_ref(), b"loan", &[bump_seed]]],
+    )?;
+
+    // Transfer SOL collateral
+    invoke(
+        &system_instruction::transfer(borrower.key, loan_account.key, required_collateral),
+        &[borrower.clone(), loan_account.clone(), system_program.clone()],
+    )?;
+
+    // Transfer USDC to borrower
+    transfer_from_program(
+        program_id,
+        token_program,
+        program_usdc_account,
+        borrower_usdc_account,
+        program_authority,
+        terms.disbursed,
+    )?;
+
+    // Route the origination fee to the treasury
+    if terms.origination_fee > 0 {
+        transfer_from_program(
+            program_id,
+            token_program,
+            program_usdc_account,
+            treasury_usdc_account,
+            program_authority,
+            terms.origination_fee,
+        )?;
+    }
+
+    // Initialize loan account data
+    let loan_data = LoanAccount {
+        borrower: *borrower.key,
+        start_date: clock.unix_timestamp,
+        principal: terms.principal,
+        apy,
+        collateral: required_collateral,
+        interest_owed: 0,
+        fees_owed: 0,
+        last_accrual: clock.unix_timestamp,
+    };
+    loan_data.serialize(&mut &mut loan_account.data.borrow_mut()[..])?;
+
+    let mut stats = load_stats(program_id, stats_account)?;
+    stats.loans_originated = stats.loans_originated.checked_add(1).ok_or(LoanError::Overflow)?;
+    stats.usdc_borrowed = stats.usdc_borrowed.checked_add(terms.principal).ok_or(LoanError::Overflow)?;
+    stats.sol_collateral_locked = stats.sol_collateral_locked.checked_add(required_collateral).ok_or(LoanError::Overflow)?;
+    stats.serialize(&mut &mut stats_account.data.borrow_mut()[..])?;
+
+    debug_msg!(
+        "Loan initialized: {} USDC borrowed against {} SOL, origination fee {} USDC",
+        terms.principal,
+        required_collateral,
+        terms.origination_fee
+    );
+
+    let result = BorrowResult {
+        principal: terms.principal,
+        disbursed: terms.disbursed,
+        collateral_locked: required_collateral,
+        origination_fee: terms.origination_fee,
+    };
+    set_return_data(&result.try_to_vec()?);
+    Ok(())
+}
+
+fn repay_loan(program_id: &Pubkey, accounts: &[AccountInfo], amount: u64, deadline: Option<i64>) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let borrower = next_account_info(account_info_iter)?;
+    let loan_account = next_account_info(account_info_iter)?;
+    let borrower_usdc_account = next_account_info(account_info_iter)?;
+    let program_usdc_account = next_account_info(account_info_iter)?;
+    let token_program = next_account_info(account_info_iter)?;
+    let clock = &Clock::from_account_info(next_account_info(account_info_iter)?)?;
+    let stats_account = next_account_info(account_info_iter)?;
+    let loan_archive = next_account_info(account_info_iter)?;
+
+    let authority = repay_authority(program_id, borrower, account_info_iter, clock.unix_timestamp)?;
+
+    check_deadline(deadline, clock.unix_timestamp)?;
+
+    check_usdc_account(borrower_usdc_account, borrower.key)?;
+    if *program_usdc_account.key != PROGRAM_USDC_ACCOUNT || *token_program.key != spl_token::id() {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let (pda, _) = Pubkey::find_program_address(&[borrower.key.as_ref(), b"loan"], program_id);
+    if pda != *loan_account.key {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let mut loan_data = load_loan_account(loan_account)?;
+    if loan_data.borrower != *borrower.key {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    if amount == 0 {
+        return Err(LoanError::InsufficientRepaymentAmount.into());
+    }
+
+    settle_loan_repayment(
+        program_id,
+        borrower,
+        loan_account,
+        &mut loan_data,
+        borrower_usdc_account,
+        program_usdc_account,
+        token_program,
+        authority,
+        &[],
+        clock,
+        stats_account,
+        loan_archive,
+        amount,
+    )?;
+    Ok(())
+}
+
+// Applies a USDC repayment of `amount` to a validated loan, pulling the
+// USDC from `borrower_usdc_account` with `authority` (signing with
+// `signers_seeds` if it is a PDA), and returns the collateral and closes the
+// loan once nothing is left due. Returns whether the loan was closed.
+#[allow(clippy::too_many_arguments)]
+fn settle_loan_repayment<'a>(
+    program_id: &Pubkey,
+    borrower: &AccountInfo<'a>,
+    loan_account: &AccountInfo<'a>,
+    loan_data: &mut LoanAccount,
+    borrower_usdc_account: &AccountInfo<'a>,
+    program_usdc_account: &AccountInfo<'a>,
+    token_program: &AccountInfo<'a>,
+    authority: &AccountInfo<'a>,
+    signers_seeds: &[&[&[u8]]],
+    clock: &Clock,
+    stats_account: &AccountInfo<'a>,
+    loan_archive: &AccountInfo<'a>,
+    amount: u64,
+) -> Result<bool, ProgramError> {
+    let breakdown = apply_loan_repayment(loan_data, amount, clock.unix_timestamp, PREPAYMENT_FEE_SCHEDULE)?;
+    let forgiven = forgive_dust(loan_data, DUST_THRESHOLD)?;
+    if forgiven > 0 {
+        debug_msg!("Forgave {} USDC of residual dust debt", forgiven);
+    }
+
+    // Transfer USDC from borrower to program
+    invoke_signed(
+        &token_instruction::transfer(
+            token_program.key,
+            borrower_usdc_account.key,
+            program_usdc_account.key,
+            authority.key,
+            &[],
+            breakdown.total(),
+        )?,
+        &[borrower_usdc_account.clone(), program_usdc_account.clone(), authority.clone(), token_program.clone()],
+        signers_seeds,
+    )?;
+
+    let mut stats = load_stats(program_id, stats_account)?;
+    stats.usdc_interest_paid = stats.usdc_interest_paid.checked_add(breakdown.interest_paid).ok_or(LoanError::Overflow)?;
+    if total_due(loan_data)? == 0 {
+        stats.sol_collateral_locked = stats.sol_collateral_locked.checked_sub(loan_data.collateral).ok_or(LoanError::Overflow)?;
+    }
+    stats.serialize(&mut &mut stats_account.data.borrow_mut()[..])?;
+
+    if total_due(loan_data)? > 0 {
+        store_loan_account(loan_account, loan_data)?;
+
+        debug_msg!(
+            "Partial repayment: {} USDC fees, {} USDC interest, {} USDC principal. Remaining principal: {} USDC",
+            breakdown.fees_paid,
+            breakdown.interest_paid,
+            breakdown.principal_paid,
+            loan_data.principal
+        );
+        set_repay_return_data(&breakdown, 0)?;
+        return Ok(false);
+    }
+
+    archive_closed_loan(program_id, loan_archive, &ClosedLoan {
+        borrower: loan_data.borrower,
+        market: LoanMarket::Usdc,
+        liquidated: false,
+        start_date: loan_data.start_date,
+        closed_at: clock.unix_timestamp,
+        collateral: loan_data.collateral,
+        final_payment: breakdown.total(),
+    })?;
+
+    // Close loan account, returning the collateral and its rent to the borrower
+    close_loan_account(borrower, loan_account)?;
+
+    debug_msg!("Loan repaid: {} USDC. Collateral returned: {} SOL", breakdown.total(), loan_data.collateral);
+    set_repay_return_data(&breakdown, loan_data.collateral)?;
+    Ok(true)
+}
+
+fn liquidate_loan(program_id: &Pubkey, accounts: &[AccountInfo], deadline: Option<i64>) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let liquidator = next_account_info(account_info_iter)?;
+    let loan_account = next_account_info(account_info_iter)?;
+    let liquidator_usdc_account = next_account_info(account_info_iter)?;
+    let program_usdc_account = next_account_info(account_info_iter)?;
+    let token_program = next_account_info(account_info_iter)?;
+    let clock = &Clock::from_account_info(next_account_info(account_info_iter)?)?;
+    let stats_account = next_account_info(account_info_iter)?;
+    let loan_archive = next_account_info(account_info_iter)?;
+    let bad_debt_account = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+
+    if !liquidator.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    check_deadline(deadline, clock.unix_timestamp)?;
+
+    check_usdc_account(liquidator_usdc_account, liquidator.key)?;
+    if *program_usdc_account.key != PROGRAM_USDC_ACCOUNT || *token_program.key != spl_token::id() {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let mut loan_data = load_loan_account(loan_account)?;
+
+    let (pda, _) = Pubkey::find_program_address(&[loan_data.borrower.as_ref(), b"loan"], program_id);
+    if pda != *loan_account.key {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    // Calculate current loan value
+    accrue(&mut loan_data, clock.unix_timestamp)?;
+    let total_due = total_due(&loan_data)?;
+
+    // Check if loan is underwater
+    let current_collateral_value = collateral_value(loan_data.collateral)?;
+    if current_collateral_value >= total_due {
+        msg!("Loan not underwater: collateral worth {} USDC, {} USDC due", current_collateral_value, total_due);
+        return Err(LoanError::LoanNotUnderwater.into());
+    }
+
+    // The liquidator pays what the collateral is worth, less the bonus,
+    // through the same waterfall; whatever that leaves unpaid is recorded as
+    // bad debt
+    let breakdown = apply_repayment(&mut loan_data, liquidation_payment(current_collateral_value)?);
+    let shortfall = total_due - breakdown.total();
+    record_bad_debt(
+        program_id,
+        liquidator,
+        bad_debt_account,
+        system_program,
+        &loan_data.borrower,
+        LoanMarket::Usdc,
+        shortfall,
+        clock.unix_timestamp,
+    )?;
+
+    // Transfer USDC from liquidator to program
+    invoke(
+        &token_instruction::transfer(
+            token_program.key,
+            liquidator_usdc_account.key,
+            program_usdc_account.key,
+            liquidator.key,
+            &[],
+            breakdown.total(),
+        )?,
+        &[liquidator_usdc_account.clone(), program_usdc_account.clone(), liquidator.clone(), token_program.clone()],
+    )?;
+
+    let mut stats = load_stats(program_id, stats_account)?;
+    stats.liquidations = stats.liquidations.checked_add(1).ok_or(LoanError::Overflow)?;
+    stats.usdc_interest_paid = stats.usdc_interest_paid.checked_add(breakdown.interest_paid).ok_or(LoanError::Overflow)?;
+    stats.sol_collateral_locked = stats.sol_collateral_locked.checked_sub(loan_data.collateral).ok_or(LoanError::Overflow)?;
+    stats.usdc_bad_debt_recorded = stats.usdc_bad_debt_recorded.checked_add(shortfall).ok_or(LoanError::Overflow)?;
+    stats.serialize(&mut &mut stats_account.data.borrow_mut()[..])?;
+
+    archive_closed_loan(program_id, loan_archive, &ClosedLoan {
+        borrower: loan_data.borrower,
+        market: LoanMarket::Usdc,
+        liquidated: true,
+        start_date: loan_data.start_date,
+        closed_at: clock.unix_timestamp,
+        collateral: loan_data.collateral,
+        final_payment: breakdown.total(),
+    })?;
+
+    // Close loan account, transferring the collateral and its rent to the liquidator
+    close_loan_account(liquidator, loan_account)?;
+
+    debug_msg!("Loan liquidated. Collateral transferred: {} SOL", loan_data.collateral);
+    set_repay_return_data(&breakdown, loan_data.collateral)
+}
+
+// Drains every lamport out of a loan account and hands it back to the system
+// program, so the same PDA can be created again for the borrower's next loan.
+fn close_loan_account(recipient: &AccountInfo, loan_account: &AccountInfo) -> ProgramResult {
+    **recipient.try_borrow_mut_lamports()? = recipient.lamports()
+        .checked_add(loan_account.lamports())
+        .ok_or(LoanError::Overflow)?;
+    **loan_account.try_borrow_mut_lamports()? = 0;
+
+    loan_account.realloc(0, false)?;
+    loan_account.assign(&system_program::id());
+    Ok(())
+}
+
+fn rollover_loan(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let borrower = next_account_info(account_info_iter)?;
+    let loan_account = next_account_info(account_info_iter)?;
+    let clock = &Clock::from_account_info(next_account_info(account_info_iter)?)?;
+    let stats_account = next_account_info(account_info_iter)?;
+
+    if !borrower.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let (pda, _) = Pubkey::find_program_address(&[borrower.key.as_ref(), b"loan"], program_id);
+    if pda != *loan_account.key {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let mut loan_data = load_loan_account(loan_account)?;
+    if loan_data.borrower != *borrower.key {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let interest = rollover(&mut loan_data, clock.unix_timestamp)?;
+    store_loan_account(loan_account, &loan_data)?;
+
+    // Capitalized interest is lent out as new principal
+    let mut stats = load_stats(program_id, stats_account)?;
+    stats.usdc_borrowed = stats.usdc_borrowed.checked_add(interest).ok_or(LoanError::Overflow)?;
+    stats.serialize(&mut &mut stats_account.data.borrow_mut()[..])?;
+
+    debug_msg!("Loan rolled over: {} USDC interest capitalized, new principal {} USDC", interest, loan_data.principal);
+    Ok(())
+}
+
+// Capitalizes accrued interest into principal and restarts the loan at `now`.
+// Outstanding fees are left as they are. Fails if the existing collateral no
+// longer covers the grown principal.
+fn rollover(loan_data: &mut LoanAccount, now: i64) -> Result<u64, ProgramError> {
+    accrue(loan_data, now)?;
+    let interest = loan_data.interest_owed;
+    let new_principal = loan_data.principal.checked_add(interest).ok_or(LoanError::Overflow)?;
+
+    let required_collateral = required_collateral(new_principal)?;
+    if loan_data.collateral < required_collateral {
+        msg!(
+            "Insufficient collateral: need {} lamports for {} USDC principal, loan holds {} lamports",
+            required_collateral,
+            new_principal,
+            loan_data.collateral
+        );
+        return Err(LoanError::InsufficientCollateral.into());
+    }
+
+    loan_data.principal = new_principal;
+    loan_data.interest_owed = 0;
+    loan_data.start_date = now;
+    Ok(interest)
+}
+
+// Grows a legacy loan account to the current layout. Anyone may pay the extra
+// rent, so integrators can migrate loans in bulk.
+fn migrate_loan(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let payer = next_account_info(account_info_iter)?;
+    let loan_account = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+    let rent = &Rent::from_account_info(next_account_info(account_info_iter)?)?;
+
+    if !payer.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if loan_account.owner != program_id || loan_account.data_len() != LEGACY_LOAN_ACCOUNT_LEN {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let loan_data = load_loan_account(loan_account)?;
+    let (usdc_loan, _) = Pubkey::find_program_address(&[loan_data.borrower.as_ref(), b"loan"], program_id);
+    let (sol_loan, _) = Pubkey::find_program_address(&[loan_data.borrower.as_ref(), b"sol_loan"], program_id);
+    if *loan_account.key != usdc_loan && *loan_account.key != sol_loan {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    // The account's lamports include SOL collateral, so only the rent for
+    // the added bytes is topped up
+    let extra_rent = rent.minimum_balance(LOAN_ACCOUNT_LEN) - rent.minimum_balance(LEGACY_LOAN_ACCOUNT_LEN);
+    invoke(
+        &system_instruction::transfer(payer.key, loan_account.key, extra_rent),
+        &[payer.clone(), loan_account.clone(), system_program.clone()],
+    )?;
+
+    loan_account.realloc(LOAN_ACCOUNT_LEN, false)?;
+    store_loan_account(loan_account, &loan_data)?;
+
+    debug_msg!("Loan account of {} migrated", loan_data.borrower);
+    Ok(())
+}
+
+fn initialize_sol_reserve(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let payer = next_account_info(account_info_iter)?;
+    let sol_reserve = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+    let rent = &Rent::from_account_info(next_account_info(account_info_iter)?)?;
+
+    if !payer.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let (pda, bump_seed) = Pubkey::find_program_address(&[b"sol_reserve"], program_id);
+    if pda != *sol_reserve.key {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    // Program-owned so lent lamports can be debited directly. Liquidity is
+    // supplied with plain system transfers into this account.
+    invoke_signed(
+        &system_instruction::create_account(
+            payer.key,
+            sol_reserve.key,
+            rent.minimum_balance(0),
+            0,
+            program_id,
+        ),
+        &[payer.clone(), sol_reserve.clone(), system_program.clone()],
+        &[&[b"sol_reserve", &[bump_seed]]],
+    )?;
+
+    debug_msg!("SOL reserve initialized");
+    Ok(())
+}
+
+fn initialize_collateral_vault(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let payer = next_account_info(account_info_iter)?;
+    let collateral_vault = next_account_info(account_info_iter)?;
+    let program_authority = next_account_info(account_info_iter)?;
+    let usdc_mint = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+    let token_program = next_account_info(account_info_iter)?;
+    let rent = &Rent::from_account_info(next_account_info(account_info_iter)?)?;
+
+    if !payer.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let (pda, bump_seed) = Pubkey::find_program_address(&[b"usdc_collateral_vault"], program_id);
+    if pda != *collateral_vault.key {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let (authority_pda, _) = Pubkey::find_program_address(&[b"authority"], program_id);
+    if authority_pda != *program_authority.key {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    if *usdc_mint.key != USDC_MINT || *token_program.key != spl_token::id() {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    // USDC collateral of SOL loans is kept apart from the lending liquidity in
+    // PROGRAM_USDC_ACCOUNT, owned by the same authority PDA
+    invoke_signed(
+        &system_instruction::create_account(
+            payer.key,
+            collateral_vault.key,
+            rent.minimum_balance(TokenAccount::LEN),
+            TokenAccount::LEN as u64,
+            &spl_token::id(),
+        ),
+        &[payer.clone(), collateral_vault.clone(), system_program.clone()],
+        &[&[b"usdc_collateral_vault", &[bump_seed]]],
+    )?;
+
+    invoke(
+        &token_instruction::initialize_account3(
+            token_program.key,
+            collateral_vault.key,
+            usdc_mint.key,
+            program_authority.key,
+        )?,
+        &[collateral_vault.clone(), usdc_mint.clone(), token_program.clone()],
+    )?;
+
+    debug_msg!("USDC collateral vault initialized");
+    Ok(())
+}
+
+fn initialize_sol_loan(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    amount: u64,
+    apy: u64,
+    max_collateral: Option<u64>,
+    deadline: Option<i64>,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let borrower = next_account_info(account_info_iter)?;
+    let loan_account = next_account_info(account_info_iter)?;
+    let borrower_usdc_account = next_account_info(account_info_iter)?;
+    let collateral_vault = next_account_info(account_info_iter)?;
+    let sol_reserve = next_account_info(account_info_iter)?;
+    let treasury_sol_account = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+    let token_program = next_account_info(account_info_iter)?;
+    let rent = &Rent::from_account_info(next_account_info(account_info_iter)?)?;
+    let clock = &Clock::from_account_info(next_account_info(account_info_iter)?)?;
+    let blocklist_marker = next_account_info(account_info_iter)?;
+    let stats_account = next_account_info(account_info_iter)?;
+    let bad_debt_account = next_account_info(account_info_iter)?;
+
+    if let Some(credential_mint) = REQUIRED_CREDENTIAL_MINT {
+        let credential_account = next_account_info(account_info_iter)?;
+        if !holds_credential(borrower.key, credential_account, &credential_mint)? {
+            return Err(LoanError::MissingCredential.into());
+        }
+    }
+
+    if let Some(allowlist) = CPI_ALLOWLIST {
+        let instructions_sysvar = next_account_info(account_info_iter)?;
+        if !cpi_guard::is_allowed_caller(program_id, instructions_sysvar, allowlist)? {
+            return Err(LoanError::UnauthorizedCpiCaller.into());
+        }
+    }
+
+    if !borrower.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    check_deadline(deadline, clock.unix_timestamp)?;
+
+    check_usdc_account(borrower_usdc_account, borrower.key)?;
+    check_collateral_vault(program_id, collateral_vault)?;
+    if *token_program.key != spl_token::id() {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    if blocklist::is_blocked(program_id, borrower.key, blocklist_marker)? {
+        return Err(LoanError::AddressBlocked.into());
+    }
+
+    check_no_bad_debt(program_id, bad_debt_account, borrower.key, LoanMarket::Sol)?;
+
+    if amount == 0 {
+        return Err(LoanError::InvalidLoanAmount.into());
+    }
+
+    if amount < MIN_SOL_LOAN_PRINCIPAL {
+        msg!("Loan amount below minimum: requested {} lamports, minimum {} lamports", amount, MIN_SOL_LOAN_PRINCIPAL);
+        return Err(LoanError::BelowMinimumLoan.into());
+    }
+
+    if *treasury_sol_account.key != TREASURY_SOL_ACCOUNT {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let (reserve_pda, _) = Pubkey::find_program_address(&[b"sol_reserve"], program_id);
+    if reserve_pda != *sol_reserve.key {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    // The reserve pays out the whole principal, fee included, and has to stay
+    // rent exempt after doing so
+    let terms = loan_terms(amount, ORIGINATION_FEE_MODE)?;
+    let available = sol_reserve.lamports().saturating_sub(rent.minimum_balance(0));
+    if terms.principal > available {
+        msg!(
+            "Insufficient liquidity: requested {} lamports, reserve has {} lamports available",
+            terms.principal,
+            available
+        );
+        return Err(LoanError::InsufficientLiquidity.into());
+    }
+
+    // Calculate required collateral
+    let required_collateral = required_usdc_collateral(terms.principal)?;
+    check_max_collateral(required_collateral, max_collateral)?;
+
+    // Create loan account
+    let (pda, bump_seed) = Pubkey::find_program_address(&[borrower.key.as_ref(), b"sol_loan"], program_id);
+    if pda != *loan_account.key {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let space = std::mem::size_of::<LoanAccount>();
+    let rent_lamports = rent.minimum_balance(space);
+
+    invoke_signed(
+        &system_instruction::create_account(
+            borrower.key,
+            loan_account.key,
+            rent_lamports,
+            space as u64,
+            program_id,
+        ),
+        &[borrower.clone(), loan_account.clone(), system_program.clone()],
+        &[&[borrower.key.as_ref(), b"sol_loan", &[bump_seed]]],
+    )?;
+
+    // Transfer USDC collateral from borrower to the collateral vault
+    invoke(
+        &token_instruction::transfer(
+            token_program.key,
+            borrower_usdc_account.key,
+            collateral_vault.key,
+            borrower.key,
+            &[],
+            required_collateral,
+        )?,
+        &[borrower_usdc_account.clone(), collateral_vault.clone(), borrower.clone(), token_program.clone()],
+    )?;
+
+    // Lend SOL from the reserve
+    **sol_reserve.try_borrow_mut_lamports()? = sol_reserve.lamports()
+        .checked_sub(terms.principal)
+        .ok_or(LoanError::InsufficientLiquidity)?;
+    **borrower.try_borrow_mut_lamports()? = borrower.lamports()
+        .checked_add(terms.disbursed)
+        .ok_or(LoanError::Overflow)?;
+
+    // Route the origination fee to the treasury
+    **treasury_sol_account.try_borrow_mut_lamports()? = treasury_sol_account.lamports()
+        .checked_add(terms.origination_fee)
+        .ok_or(LoanError::Overflow)?;
+
+    // Initialize loan account data
+    let loan_data = LoanAccount {
+        borrower: *borrower.key,
+        start_date: clock.unix_timestamp,
+        principal: terms.principal,
+        apy,
+        collateral: required_collateral,
+        interest_owed: 0,
+        fees_owed: 0,
+        last_accrual: clock.unix_timestamp,
+    };
+    loan_data.serialize(&mut &mut loan_account.data.borrow_mut()[..])?;
+
+    let mut stats = load_stats(program_id, stats_account)?;
+    stats.loans_originated = stats.loans_originated.checked_add(1).ok_or(LoanError::Overflow)?;
+    stats.sol_borrowed = stats.sol_borrowed.checked_add(terms.principal).ok_or(LoanError::Overflow)?;
+    stats.usdc_collateral_locked = stats.usdc_collateral_locked.checked_add(required_collateral).ok_or(LoanError::Overflow)?;
+    stats.serialize(&mut &mut stats_account.data.borrow_mut()[..])?;
+
+    debug_msg!(
+        "SOL loan initialized: {} lamports borrowed against {} USDC, origination fee {} lamports",
+        terms.principal,
+        required_collateral,
+        terms.origination_fee
+    );
+
+    let result = BorrowResult {
+        principal: terms.principal,
+        disbursed: terms.disbursed,
+        collateral_locked: required_collateral,
+        origination_fee: terms.origination_fee,
+    };
+    set_return_data(&result.try_to_vec()?);
+    Ok(())
+}
+
+fn repay_sol_loan(program_id: &Pubkey, accounts: &[AccountInfo], amount: u64, deadline: Option<i64>) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let borrower = next_account_info(account_info_iter)?;
+    let loan_account = next_account_info(account_info_iter)?;
+    let borrower_usdc_account = next_account_info(account_info_iter)?;
+    let collateral_vault = next_account_info(account_info_iter)?;
+    let sol_reserve = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+    let token_program = next_account_info(account_info_iter)?;
+    let program_authority = next_account_info(account_info_iter)?;
+    let clock = &Clock::from_account_info(next_account_info(account_info_iter)?)?;
+    let stats_account = next_account_info(account_info_iter)?;
+    let loan_archive = next_account_info(account_info_iter)?;
+
+    let authority = repay_authority(program_id, borrower, account_info_iter, clock.unix_timestamp)?;
+
+    check_deadline(deadline, clock.unix_timestamp)?;
+
+    check_usdc_account(borrower_usdc_account, borrower.key)?;
+    check_collateral_vault(program_id, collateral_vault)?;
+    if *token_program.key != spl_token::id() {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let (pda, _) = Pubkey::find_program_address(&[borrower.key.as_ref(), b"sol_loan"], program_id);
+    if pda != *loan_account.key {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let (reserve_pda, _) = Pubkey::find_program_address(&[b"sol_reserve"], program_id);
+    if reserve_pda != *sol_reserve.key {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let mut loan_data = load_loan_account(loan_account)?;
+    if loan_data.borrower != *borrower.key {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    if amount == 0 {
+        return Err(LoanError::InsufficientRepaymentAmount.into());
+    }
+
+    let breakdown = apply_loan_repayment(&mut loan_data, amount, clock.unix_timestamp, PREPAYMENT_FEE_SCHEDULE)?;
+    let forgiven = forgive_dust(&mut loan_data, SOL_DUST_THRESHOLD)?;
+    if forgiven > 0 {
+        debug_msg!("Forgave {} lamports of residual dust debt", forgiven);
+    }
+
+    // Return SOL to the reserve
+    invoke(
+        &system_instruction::transfer(authority.key, sol_reserve.key, breakdown.total()),
+        &[authority.clone(), sol_reserve.clone(), system_program.clone()],
+    )?;
+
+    let mut stats = load_stats(program_id, stats_account)?;
+    stats.sol_interest_paid = stats.sol_interest_paid.checked_add(breakdown.interest_paid).ok_or(LoanError::Overflow)?;
+    if total_due(&loan_data)? == 0 {
+        stats.usdc_collateral_locked = stats.usdc_collateral_locked.checked_sub(loan_data.collateral).ok_or(LoanError::Overflow)?;
+    }
+    stats.serialize(&mut &mut stats_account.data.borrow_mut()[..])?;
+
+    if total_due(&loan_data)? > 0 {
+        store_loan_account(loan_account, &loan_data)?;
+
+        debug_msg!(
+            "Partial SOL repayment: {} lamports interest, {} lamports principal. Remaining principal: {} lamports",
+            breakdown.interest_paid,
+            breakdown.principal_paid,
+            loan_data.principal
+        );
+        return set_repay_return_data(&breakdown, 0);
+    }
+
+    // Return USDC collateral to borrower
+    transfer_from_program(
+        program_id,
+        token_program,
+        collateral_vault,
+        borrower_usdc_account,
+        program_authority,
+        loan_data.collateral,
+    )?;
+
+    archive_closed_loan(program_id, loan_archive, &ClosedLoan {
+        borrower: loan_data.borrower,
+        market: LoanMarket::Sol,
+        liquidated: false,
+        start_date: loan_data.start_date,
+        closed_at: clock.unix_timestamp,
+        collateral: loan_data.collateral,
+        final_payment: breakdown.total(),
+    })?;
+
+    // Close loan account, refunding its rent to the borrower
+    close_loan_account(borrower, loan_account)?;
+
+    debug_msg!("SOL loan repaid: {} lamports. Collateral returned: {} USDC", breakdown.total(), loan_data.collateral);
+    set_repay_return_data(&breakdown, loan_data.collateral)
+}
+
+fn liquidate_sol_loan(program_id: &Pubkey, accounts: &[AccountInfo], deadline: Option<i64>) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let liquidator = next_account_info(account_info_iter)?;
+    let loan_account = next_account_info(account_info_iter)?;
+    let liquidator_usdc_account = next_account_info(account_info_iter)?;
+    let collateral_vault = next_account_info(account_info_iter)?;
+    let sol_reserve = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+    let token_program = next_account_info(account_info_iter)?;
+    let program_authority = next_account_info(account_info_iter)?;
+    let clock = &Clock::from_account_info(next_account_info(account_info_iter)?)?;
+    let stats_account = next_account_info(account_info_iter)?;
+    let loan_archive = next_account_info(account_info_iter)?;
+    let bad_debt_account = next_account_info(account_info_iter)?;
+
+    if !liquidator.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    check_deadline(deadline, clock.unix_timestamp)?;
+
+    check_usdc_account(liquidator_usdc_account, liquidator.key)?;
+    check_collateral_vault(program_id, collateral_vault)?;
+    if *token_program.key != spl_token::id() {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let (reserve_pda, _) = Pubkey::find_program_address(&[b"sol_reserve"], program_id);
+    if reserve_pda != *sol_reserve.key {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let mut loan_data = load_loan_account(loan_account)?;
+
+    let (pda, _) = Pubkey::find_program_address(&[loan_data.borrower.as_ref(), b"sol_loan"], program_id);
+    if pda != *loan_account.key {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    // Calculate current loan value
+    accrue(&mut loan_data, clock.unix_timestamp)?;
+    let total_due = total_due(&loan_data)?;
+
+    // Check if loan is underwater
+    let debt_value = sol_debt_value(total_due)?;
+    if loan_data.collateral >= debt_value {
+        msg!("Loan not underwater: {} USDC collateral, debt worth {} USDC", loan_data.collateral, debt_value);
+        return Err(LoanError::LoanNotUnderwater.into());
+    }
+
+    // The liquidator pays what the collateral is worth, less the bonus,
+    // through the same waterfall; whatever that leaves unpaid is recorded as
+    // bad debt
+    let breakdown = apply_repayment(&mut loan_data, liquidation_payment(usdc_collateral_sol_value(loan_data.collateral)?)?);
+    let shortfall = total_due - breakdown.total();
+    record_bad_debt(
+        program_id,
+        liquidator,
+        bad_debt_account,
+        system_program,
+        &loan_data.borrower,
+        LoanMarket::Sol,
+        shortfall,
+        clock.unix_timestamp,
+    )?;
+
+    // Transfer SOL from liquidator to the reserve
+    invoke(
+        &system_instruction::transfer(liquidator.key, sol_reserve.key, breakdown.total()),
+        &[liquidator.clone(), sol_reserve.clone(), system_program.clone()],
+    )?;
+
+    // Transfer USDC collateral to liquidator
+    transfer_from_program(
+        program_id,
+        token_program,
+        collateral_vault,
+        liquidator_usdc_account,
+        program_authority,
+        loan_data.collateral,
+    )?;
+
+    let mut stats = load_stats(program_id, stats_account)?;
+    stats.liquidations = stats.liquidations.checked_add(1).ok_or(LoanError::Overflow)?;
+    stats.sol_interest_paid = stats.sol_interest_paid.checked_add(breakdown.interest_paid).ok_or(LoanError::Overflow)?;
+    stats.usdc_collateral_locked = stats.usdc_collateral_locked.checked_sub(loan_data.collateral).ok_or(LoanError::Overflow)?;
+    stats.sol_bad_debt_recorded = stats.sol_bad_debt_recorded.checked_add(shortfall).ok_or(LoanError::Overflow)?;
+    stats.serialize(&mut &mut stats_account.data.borrow_mut()[..])?;
+
+    archive_closed_loan(program_id, loan_archive, &ClosedLoan {
+        borrower: loan_data.borrower,
+        market: LoanMarket::Sol,
+        liquidated: true,
+        start_date: loan_data.start_date,
+        closed_at: clock.unix_timestamp,
+        collateral: loan_data.collateral,
+        final_payment: breakdown.total(),
+    })?;
+
+    // Close loan account, paying its rent to the liquidator
+    close_loan_account(liquidator, loan_account)?;
+
+    debug_msg!("SOL loan liquidated. Collateral transferred: {} USDC", loan_data.collateral);
+    set_repay_return_data(&breakdown, loan_data.collateral)
+}
+
+fn repay_bad_debt(program_id: &Pubkey, accounts: &[AccountInfo], amount: u64) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let payer = next_account_info(account_info_iter)?;
+    let bad_debt_account = next_account_info(account_info_iter)?;
+    let payer_usdc_account = next_account_info(account_info_iter)?;
+    let program_usdc_account = next_account_info(account_info_iter)?;
+    let token_program = next_account_info(account_info_iter)?;
+    let stats_account = next_account_info(account_info_iter)?;
+    let funder = next_account_info(account_info_iter)?;
+
+    if !payer.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    check_usdc_account(payer_usdc_account, payer.key)?;
+    if *program_usdc_account.key != PROGRAM_USDC_ACCOUNT || *token_program.key != spl_token::id() {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let paid = settle_bad_debt(program_id, funder, bad_debt_account, LoanMarket::Usdc, amount)?;
+
+    invoke(
+        &token_instruction::transfer(
+            token_program.key,
+            payer_usdc_account.key,
+            program_usdc_account.key,
+            payer.key,
+            &[],
+            paid,
+        )?,
+        &[payer_usdc_account.clone(), program_usdc_account.clone(), payer.clone(), token_program.clone()],
+    )?;
+
+    let mut stats = load_stats(program_id, stats_account)?;
+    stats.usdc_bad_debt_repaid = stats.usdc_bad_debt_repaid.checked_add(paid).ok_or(LoanError::Overflow)?;
+    stats.serialize(&mut &mut stats_account.data.borrow_mut()[..])?;
+
+    debug_msg!("Bad debt repaid: {} USDC", paid);
+    Ok(())
+}
+
+fn repay_sol_bad_debt(program_id: &Pubkey, accounts: &[AccountInfo], amount: u64) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let payer = next_account_info(account_info_iter)?;
+    let bad_debt_account = next_account_info(account_info_iter)?;
+    let sol_reserve = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+    let stats_account = next_account_info(account_info_iter)?;
+    let funder = next_account_info(account_info_iter)?;
+
+    if !payer.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let (reserve_pda, _) = Pubkey::find_program_address(&[b"sol_reserve"], program_id);
+    if reserve_pda != *sol_reserve.key {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let paid = settle_bad_debt(program_id, funder, bad_debt_account, LoanMarket::Sol, amount)?;
+
+    invoke(
+        &system_instruction::transfer(payer.key, sol_reserve.key, paid),
+        &[payer.clone(), sol_reserve.clone(), system_program.clone()],
+    )?;
+
+    let mut stats = load_stats(program_id, stats_account)?;
+    stats.sol_bad_debt_repaid = stats.sol_bad_debt_repaid.checked_add(paid).ok_or(LoanError::Overflow)?;
+    stats.serialize(&mut &mut stats_account.data.borrow_mut()[..])?;
+
+    debug_msg!("Bad debt repaid: {} lamports", paid);
+    Ok(())
+}
+
+fn block_address(program_id: &Pubkey, accounts: &[AccountInfo], address: Pubkey) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let admin = next_account_info(account_info_iter)?;
+    let blocklist_marker = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+    let rent = &Rent::from_account_info(next_account_info(account_info_iter)?)?;
+
+    if !admin.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if *admin.key != ADMIN {
+        return Err(LoanError::Unauthorized.into());
+    }
+
+    blocklist::block(program_id, &address, admin, blocklist_marker, system_program, rent)?;
+
+    msg!("Address blocked: {}", address);
+    Ok(())
+}
+
+fn unblock_address(program_id: &Pubkey, accounts: &[AccountInfo], address: Pubkey) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let admin = next_account_info(account_info_iter)?;
+    let blocklist_marker = next_account_info(account_info_iter)?;
+
+    if !admin.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if *admin.key != ADMIN {
+        return Err(LoanError::Unauthorized.into());
+    }
+
+    blocklist::unblock(program_id, &address, admin, blocklist_marker)?;
+
+    msg!("Address unblocked: {}", address);
+    Ok(())
+}
+
+fn initialize_stats(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let payer = next_account_info(account_info_iter)?;
+    let stats_account = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+    let rent = &Rent::from_account_info(next_account_info(account_info_iter)?)?;
+
+    if !payer.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let (pda, bump_seed) = Pubkey::find_program_address(&[b"stats"], program_id);
+    if pda != *stats_account.key {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let space = std::mem::size_of::<Stats>();
+    invoke_signed(
+        &system_instruction::create_account(
+            payer.key,
+            stats_account.key,
+            rent.minimum_balance(space),
+            space as u64,
+            program_id,
+        ),
+        &[payer.clone(), stats_account.clone(), system_program.clone()],
+        &[&[b"stats", &[bump_seed]]],
+    )?;
+
+    Stats::default().serialize(&mut &mut stats_account.data.borrow_mut()[..])?;
+
+    debug_msg!("Stats account initialized");
+    Ok(())
+}
+
+fn initialize_loan_archive(program_id: &Pubkey, accounts: &[AccountInfo], shard: u8) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let payer = next_account_info(account_info_iter)?;
+    let loan_archive = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+    let rent = &Rent::from_account_info(next_account_info(account_info_iter)?)?;
+
+    if !payer.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if shard >= LOAN_ARCHIVE_SHARDS {
+        return Err(LoanError::InvalidInstruction.into());
+    }
+
+    let (pda, bump_seed) = Pubkey::find_program_address(&[b"loan_archive", &[shard]], program_id);
+    if pda != *loan_archive.key {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    // Allocated at full capacity up front, so archiving never reallocates
+    invoke_signed(
+        &system_instruction::create_account(
+            payer.key,
+            loan_archive.key,
+            rent.minimum_balance(LOAN_ARCHIVE_LEN),
+            LOAN_ARCHIVE_LEN as u64,
+            program_id,
+        ),
+        &[payer.clone(), loan_archive.clone(), system_program.clone()],
+        &[&[b"loan_archive", &[shard], &[bump_seed]]],
+    )?;
+
+    debug_msg!("Loan archive shard {} initialized", shard);
+    Ok(())
+}
+
+fn announce_emergency_sweep(program_id: &Pubkey, accounts: &[AccountInfo], amount: u64) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let admin = next_account_info(account_info_iter)?;
+    let sweep_account = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+    let rent = &Rent::from_account_info(next_account_info(account_info_iter)?)?;
+    let clock = &Clock::from_account_info(next_account_info(account_info_iter)?)?;
+
+    if !admin.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if *admin.key != ADMIN {
+        return Err(LoanError::Unauthorized.into());
+    }
+
+    let (pda, bump_seed) = Pubkey::find_program_address(&[b"sweep"], program_id);
+    if pda != *sweep_account.key {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let space = std::mem::size_of::<SweepProposal>();
+    invoke_signed(
+        &system_instruction::create_account(
+            admin.key,
+            sweep_account.key,
+            rent.minimum_balance(space),
+            space as u64,
+            program_id,
+        ),
+        &[admin.clone(), sweep_account.clone(), system_program.clone()],
+        &[&[b"sweep", &[bump_seed]]],
+    )?;
+
+    let sweep_data = SweepProposal {
+        amount,
+        executable_at: clock.unix_timestamp + EMERGENCY_SWEEP_DELAY,
+    };
+    sweep_data.serialize(&mut &mut sweep_account.data.borrow_mut()[..])?;
+
+    msg!("Emergency sweep announced: {} USDC to treasury, executable at {}", amount, sweep_data.executable_at);
+    Ok(())
+}
+
+fn execute_emergency_sweep(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let admin = next_account_info(account_info_iter)?;
+    let sweep_account = next_account_info(account_info_iter)?;
+    let program_usdc_account = next_account_info(account_info_iter)?;
+    let treasury_usdc_account = next_account_info(account_info_iter)?;
+    let token_program = next_account_info(account_info_iter)?;
+    let program_authority = next_account_info(account_info_iter)?;
+    let clock = &Clock::from_account_info(next_account_info(account_info_iter)?)?;
+
+    if !admin.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if *admin.key != ADMIN {
+        return Err(LoanError::Unauthorized.into());
+    }
+
+    let (pda, _) = Pubkey::find_program_address(&[b"sweep"], program_id);
+    if pda != *sweep_account.key {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    // Only the USDC market's liquidity can be swept. Collateral of SOL loans
+    // sits in the collateral vault, which no sweep ever touches.
+    if *program_usdc_account.key != PROGRAM_USDC_ACCOUNT || *treasury_usdc_account.key != TREASURY_USDC_ACCOUNT {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    if *token_program.key != spl_token::id() {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let sweep_data = SweepProposal::try_from_slice(&sweep_account.data.borrow())?;
+    if clock.unix_timestamp < sweep_data.executable_at {
+        msg!("Sweep not ready: executable at {}, now {}", sweep_data.executable_at, clock.unix_timestamp);
+        return Err(LoanError::SweepNotReady.into());
+    }
+
+    transfer_from_program(
+        program_id,
+        token_program,
+        program_usdc_account,
+        treasury_usdc_account,
+        program_authority,
+        sweep_data.amount,
+    )?;
+
+    close_sweep_account(admin, sweep_account)?;
+
+    msg!("Emergency sweep executed: {} USDC moved to treasury", sweep_data.amount);
+    Ok(())
+}
+
+fn cancel_emergency_sweep(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let admin = next_account_info(account_info_iter)?;
+    let sweep_account = next_account_info(account_info_iter)?;
+
+    if !admin.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if *admin.key != ADMIN {
+        return Err(LoanError::Unauthorized.into());
+    }
+
+    let (pda, _) = Pubkey::find_program_address(&[b"sweep"], program_id);
+    if pda != *sweep_account.key {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    close_sweep_account(admin, sweep_account)?;
+
+    msg!("Emergency sweep cancelled");
+    Ok(())
+}
+
+// Closes the sweep proposal, returning its rent to the admin
+fn close_sweep_account(admin: &AccountInfo, sweep_account: &AccountInfo) -> ProgramResult {
+    **admin.try_borrow_mut_lamports()? = admin.lamports()
+        .checked_add(sweep_account.lamports())
+        .ok_or(LoanError::Overflow)?;
+    **sweep_account.try_borrow_mut_lamports()? = 0;
+
+    sweep_account.realloc(0, false)?;
+    sweep_account.assign(&system_program::id());
+    Ok(())
+}
+
+fn register_session(program_id: &Pubkey, accounts: &[AccountInfo], session_key: Pubkey, expiry: i64) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let borrower = next_account_info(account_info_iter)?;
+    let session_account = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
+    let rent = &Rent::from_account_info(next_account_info(account_info_iter)?)?;
+
+    if !borrower.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let (pda, bump_seed) = Pubkey::find_program_address(&[borrower.key.as_ref(), b"session"], program_id);
+    if pda != *session_account.key {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    // Registering again replaces the current session
+    if session_account.owner != program_id {
+        let space = std::mem::size_of::<Session>();
+        invoke_signed(
+            &system_instruction::create_account(
+                borrower.key,
+                session_account.key,
+                rent.minimum_balance(space),
+                space as u64,
+                program_id,
+            ),
+            &[borrower.clone(), session_account.clone(), system_program.clone()],
+            &[&[borrower.key.as_ref(), b"session", &[bump_seed]]],
+        )?;
+    }
+
+    Session { session_key, expiry }.serialize(&mut &mut session_account.data.borrow_mut()[..])?;
+
+    debug_msg!("Session key {} registered until {}", session_key, expiry);
+    Ok(())
+}
+
+fn revoke_session(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let borrower = next_account_info(account_info_iter)?;
+    let session_account = next_account_info(account_info_iter)?;
+
+    if !borrower.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let (pda, _) = Pubkey::find_program_address(&[borrower.key.as_ref(), b"session"], program_id);
+    if pda != *session_account.key || session_account.owner != program_id {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    // Close the session, returning its rent to the borrower
+    **borrower.try_borrow_mut_lamports()? = borrower.lamports()
+        .checked_add(session_account.lamports())
+        .ok_or(LoanError::Overflow)?;
+    **session_account.try_borrow_mut_lamports()? = 0;
+
+    session_account.realloc(0, false)?;
+    session_account.assign(&system_program::id());
+
+    debug_msg!("Session revoked");
+    Ok(())
+}
+
+fn create_repayment_schedule(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    amount: u64,
+    interval: i64,
+    first_due: i64,
+) -> ProgramResult {
+    let account_info_iter = &mut accounts.iter();
+    let borrower = next_account_info(account_info_iter)?;
+    let schedule_account = next_account_info(account_info_iter)?;
+    let funding_account = next_account_info(account_info_iter)?;
+    let system_program = next_account_info(account_info_iter)?;
     let rent = &Rent::from_account_info(next_account_info(account_info_iter)?)?;
-    let clock = &Clock::from_account_info(next_account_info(account_info_iter)?)?;
 
     if !borrower.is_signer {
         return Err(ProgramError::MissingRequiredSignature);
     }
 
-    if amount == 0 {
-        return Err(LoanError::InvalidLoanAmount.into());
+    if amount == 0 || interval <= 0 {
+        return Err(LoanError::InvalidInstruction.into());
     }
 
-    // Calculate required collateral
-    let required_collateral = (amount * 100) / (SOL_PRICE * LTV);
+    check_usdc_account(funding_account, borrower.key)?;
 
-    // Create loan account
-    let (pda, bump_seed) = Pubkey::find_program_address(&[borrower.key.as_ref(), b"loan"], program_id);
-    if pda != *loan_account.key {
+    let (pda, bump_seed) = Pubkey::find_program_address(&[borrower.key.as_ref(), b"repayment_schedule"], program_id);
+    if pda != *schedule_account.key {
         return Err(ProgramError::InvalidAccountData);
     }
 
-    if !rent.is_exempt(loan_account.lamports(), loan_account.data_len()) {
-        return Err(LoanError::NotRentExempt.into());
+    // Creating a schedule again replaces the current one
+    if schedule_account.owner != program_id {
+        let space = std::mem::size_of::<RepaymentSchedule>();
+        invoke_signed(
+            &system_instruction::create_account(
+                borrower.key,
+                schedule_account.key,
+                rent.minimum_balance(space),
+                space as u64,
+                program_id,
+            ),
+            &[borrower.clone(), schedule_account.clone(), system_program.clone()],
+            &[&[borrower.key.as_ref(), b"repayment_schedule", &[bump_seed]]],
+        )?;
     }
 
-    let space = std::mem::size_of::<LoanAccount>();
-    let rent_lamports = rent.minimum_balance(space);
-
-    invoke_signed(
-        &system_instruction::create_account(
-            borrower.key,
-            loan_account.key,
-            rent_lamports,
-            space as u64,
-            program_id,
-        ),
-        &[borrower.clone(), loan_account.clone(), system_program.clone()],
-        &[&[borrower.key.as_ref(), b"loan", &[bump_seed]]],
-    )?;
-
-    // Transfer SOL collateral
-    invoke(
-        &system_instruction::transfer(borrower.key, loan_account.key, required_collateral),
-        &[borrower.clone(), loan_account.clone(), system_program.clone()],
-    )?;
-
-    // Transfer USDC to borrower
-    invoke(
-        &token_instruction::transfer(
-            token_program.key,
-            program_usdc_account.key,
-            borrower_usdc_account.key,
-            &program_id,
-            &[],
-            amount,
-        )?,
-        &[program_usdc_account.clone(), borrower_usdc_account.clone(), token_program.clone()],
-    )?;
-
-    // Initialize loan account data
-    let loan_data = LoanAccount {
+    RepaymentSchedule {
         borrower: *borrower.key,
-        start_date: clock.unix_timestamp,
-        principal: amount,
-        apy,
-        collateral: required_collateral,
-    };
-    loan_data.serialize(&mut &mut loan_account.data.borrow_mut()[..])?;
+        funding_account: *funding_account.key,
+        amount,
+        interval,
+        next_due: first_due,
+    }
+    .serialize(&mut &mut schedule_account.data.borrow_mut()[..])?;
 
-    msg!("Loan initialized: {} USDC borrowed against {} SOL", amount, required_collateral);
+    debug_msg!("Repayment schedule created: {} USDC every {} seconds from {}", amount, interval, first_due);
     Ok(())
 }
 
-fn repay_loan(accounts: &[AccountInfo], amount: u64) -> ProgramResult {
+fn cancel_repayment_schedule(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
     let account_info_iter = &mut accounts.iter();
     let borrower = next_account_info(account_info_iter)?;
-    let loan_account = next_account_info(account_info_iter)?;
-    let borrower_usdc_account = next_account_info(account_info_iter)?;
-    let program_usdc_account = next_account_info(account_info_iter)?;
-    let token_program = next_account_info(account_info_iter)?;
-    let clock = &Clock::from_account_info(next_account_info(account_info_iter)?)?;
+    let schedule_account = next_account_info(account_info_iter)?;
 
     if !borrower.is_signer {
         return Err(ProgramError::MissingRequiredSignature);
     }
 
-    let mut loan_data = LoanAccount::try_from_slice(&loan_account.data.borrow())?;
-    if loan_data.borrower != *borrower.key {
+    let (pda, _) = Pubkey::find_program_address(&[borrower.key.as_ref(), b"repayment_schedule"], program_id);
+    if pda != *schedule_account.key || schedule_account.owner != program_id {
         return Err(ProgramError::InvalidAccountData);
     }
 
-    // Calculate interest
-    let time_elapsed = (clock.unix_timestamp - loan_data.start_date) as u64;
-    let interest = (loan_data.principal * loan_data.apy * time_elapsed) / (365 * 24 * 60 * 60 * 100);
-    let total_due = loan_data.principal.checked_add(interest).ok_or(LoanError::Overflow)?;
-
-    if amount < total_due {
-        return Err(LoanError::InsufficientRepaymentAmount.into());
-    }
+    close_repayment_schedule(borrower, schedule_account)?;
 
-    // Transfer USDC from borrower to program
-    invoke(
-        &token_instruction::transfer(
-            token_program.key,
-            borrower_usdc_account.key,
-            program_usdc_account.key,
-            borrower.key,
-            &[],
-            amount,
-        )?,
-        &[borrower_usdc_account.clone(), program_usdc_account.clone(), borrower.clone(), token_program.clone()],
-    )?;
+    debug_msg!("Repayment schedule cancelled");
+    Ok(())
+}
 
-    // Return collateral to borrower
-    **loan_account.try_borrow_mut_lamports()? = loan_account.lamports()
-        .checked_sub(loan_data.collateral)
-        .ok_or(ProgramError::InsufficientFunds)?;
+// Closes a repayment schedule, returning its rent to the borrower
+fn close_repayment_schedule(borrower: &AccountInfo, schedule_account: &AccountInfo) -> ProgramResult {
     **borrower.try_borrow_mut_lamports()? = borrower.lamports()
-        .checked_add(loan_data.collateral)
+        .checked_add(schedule_account.lamports())
         .ok_or(LoanError::Overflow)?;
+    **schedule_account.try_borrow_mut_lamports()? = 0;
 
-    // Close loan account
-    loan_account.assign(system_program::id());
-    loan_account.realloc(0, false)?;
-
-    msg!("Loan repaid: {} USDC. Collateral returned: {} SOL", amount, loan_data.collateral);
+    schedule_account.realloc(0, false)?;
+    schedule_account.assign(&system_program::id());
     Ok(())
 }
 
-fn liquidate_loan(accounts: &[AccountInfo]) -> ProgramResult {
+// Returns the installment a schedule pays now: its amount, capped at what the
+// loan still owes. Fails until the installment is due.
+fn scheduled_installment(schedule: &RepaymentSchedule, amount_due: u64, now: i64) -> Result<u64, ProgramError> {
+    if now < schedule.next_due {
+        msg!("Repayment not due: due at {}, now {}", schedule.next_due, now);
+        return Err(LoanError::RepaymentNotDue.into());
+    }
+
+    Ok(schedule.amount.min(amount_due))
+}
+
+// Permissionless crank: anyone, such as an automation keeper, may pay a due
+// installment on the borrower's behalf from the schedule's funding account
+fn execute_scheduled_repayment(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
     let account_info_iter = &mut accounts.iter();
-    let liquidator = next_account_info(account_info_iter)?;
+    let schedule_account = next_account_info(account_info_iter)?;
+    let borrower = next_account_info(account_info_iter)?;
     let loan_account = next_account_info(account_info_iter)?;
-    let liquidator_usdc_account = next_account_info(account_info_iter)?;
+    let funding_account = next_account_info(account_info_iter)?;
     let program_usdc_account = next_account_info(account_info_iter)?;
     let token_program = next_account_info(account_info_iter)?;
     let clock = &Clock::from_account_info(next_account_info(account_info_iter)?)?;
+    let stats_account = next_account_info(account_info_iter)?;
+    let loan_archive = next_account_info(account_info_iter)?;
 
-    if !liquidator.is_signer {
-        return Err(ProgramError::MissingRequiredSignature);
+    let (pda, bump_seed) = Pubkey::find_program_address(&[borrower.key.as_ref(), b"repayment_schedule"], program_id);
+    if pda != *schedule_account.key || schedule_account.owner != program_id {
+        return Err(ProgramError::InvalidAccountData);
     }
 
-    let loan_data = LoanAccount::try_from_slice(&loan_account.data.borrow())?;
+    let mut schedule = RepaymentSchedule::try_from_slice(&schedule_account.data.borrow())?;
+    if schedule.borrower != *borrower.key || schedule.funding_account != *funding_account.key {
+        return Err(ProgramError::InvalidAccountData);
+    }
 
-    // Calculate current loan value
-    let time_elapsed = (clock.unix_timestamp - loan_data.start_date) as u64;
-    let interest = (loan_data.principal * loan_data.apy * time_elapsed) / (365 * 24 * 60 * 60 * 100);
-    let total_due = loan_data.principal.checked_add(interest).ok_or(LoanError::Overflow)?;
+    check_usdc_account(funding_account, borrower.key)?;
+    if *program_usdc_account.key != PROGRAM_USDC_ACCOUNT || *token_program.key != spl_token::id() {
+        return Err(ProgramError::InvalidAccountData);
+    }
 
-    // Check if loan is underwater
-    let current_collateral_value = (loan_data.collateral * SOL_PRICE) / 100;
-    if current_collateral_value >= total_due {
-        return Err(LoanError::LoanNotUnderwater.into());
+    let (pda, _) = Pubkey::find_program_address(&[borrower.key.as_ref(), b"loan"], program_id);
+    if pda != *loan_account.key {
+        return Err(ProgramError::InvalidAccountData);
     }
 
-    // Transfer USDC from liquidator to program
-    invoke(
-        &token_instruction::transfer(
-            token_program.key,
-            liquidator_usdc_account.key,
-            program_usdc_account.key,
-            liquidator.key,
-            &[],
-            total_due,
-        )?,
-        &[liquidator_usdc_account.clone(), program_usdc_account.clone(), liquidator.clone(), token_program.clone()],
+    let mut loan_data = load_loan_account(loan_account)?;
+    if loan_data.borrower != *borrower.key {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    accrue(&mut loan_data, clock.unix_timestamp)?;
+    let installment = scheduled_installment(&schedule, total_due(&loan_data)?, clock.unix_timestamp)?;
+
+    let closed = settle_loan_repayment(
+        program_id,
+        borrower,
+        loan_account,
+        &mut loan_data,
+        funding_account,
+        program_usdc_account,
+        token_program,
+        schedule_account,
+        &[&[borrower.key.as_ref(), b"repayment_schedule", &[bump_seed]]],
+        clock,
+        stats_account,
+        loan_archive,
+        installment,
     )?;
 
-    // Transfer collateral to liquidator
-    **loan_account.try_borrow_mut_lamports()? = loan_account.lamports()
-        .checked_sub(loan_data.collateral)
-        .ok_or(ProgramError::InsufficientFunds)?;
-    **liquidator.try_borrow_mut_lamports()? = liquidator.lamports()
-        .checked_add(loan_data.collateral)
-        .ok_or(LoanError::Overflow)?;
+    // A paid-off loan leaves the schedule nothing to repay
+    if closed {
+        return close_repayment_schedule(borrower, schedule_account);
+    }
 
-    // Close loan account
-    loan_account.assign(system_program::id());
-    loan_account.realloc(0, false)?;
+    schedule.next_due = schedule.next_due.checked_add(schedule.interval).ok_or(LoanError::Overflow)?;
+    schedule.serialize(&mut &mut schedule_account.data.borrow_mut()[..])?;
 
-    msg!("Loan liquidated. Collateral transferred: {} SOL", loan_data.collateral);
+    debug_msg!("Scheduled repayment of {} USDC executed, next due at {}", installment, schedule.next_due);
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use solana_program::clock::Epoch;
+    use super::test_utils::{clock_sysvar_data, rent_sysvar_data, serialize_input, test_account};
+    use solana_program::{clock::Epoch, entrypoint};
     use std::mem;
 
     // Helper function to create AccountInfo for testing
@@ -315,219 +2623,875 @@ mod tests {
     fn test_initialize_loan() {
         let program_id = Pubkey::new_unique();
         let borrower_key = Pubkey::new_unique();
-        let loan_account_key = Pubkey::new_unique();
-        let usdc_mint_key = Pubkey::new_unique();
-        let borrower_usdc_account_key = Pubkey::new_unique();
-        let program_usdc_account_key = Pubkey::new_unique();
-
-        let mut borrower_lamports = 1000000000; // 10 SOL
-        let mut loan_account_lamports = 0;
-        let mut borrower_usdc_lamports = 1000000; // 1 USDC
-        let mut program_usdc_lamports = 1000000000; // 1000 USDC
-
-        let mut loan_account_data = vec![0; mem::size_of::<LoanAccount>()];
-        let mut borrower_usdc_data = vec![0; 165]; // Mocked SPL Token account data
-        let mut program_usdc_data = vec![0; 165]; // Mocked SPL Token account data
-
-        let borrower_account = create_account_info(&borrower_key, true, &mut borrower_lamports, &mut [], &program_id);
-        let loan_account = create_account_info(&loan_account_key, false, &mut loan_account_lamports, &mut loan_account_data, &program_id);
-        let borrower_usdc_account = create_account_info(&borrower_usdc_account_key, false, &mut borrower_usdc_lamports, &mut borrower_usdc_data, &usdc_mint_key);
-        let program_usdc_account = create_account_info(&program_usdc_account_key, false, &mut program_usdc_lamports, &mut program_usdc_data, &usdc_mint_key);
-
-        let system_program_key = Pubkey::new_unique();
-        let token_program_key = Pubkey::new_unique();
-        let rent_key = Pubkey::new_unique();
-        let clock_key = Pubkey::new_unique();
-
-        let accounts = vec![
-            borrower_account, loan_account,
-            borrower_usdc_account,
-            program_usdc_account,
-            create_account_info(&system_program_key, false, &mut 0, &mut [], &program_id),
-            create_account_info(&token_program_key, false, &mut 0, &mut [], &program_id),
-            create_account_info(&rent_key, false, &mut 0, &mut [], &program_id),
-            create_account_info(&clock_key, false, &mut 0, &mut [], &program_id),
-        ];
-
-        let amount = 100_000_000; // 100 USDC
-        let apy = 500; // 5% APY
-
-        let instruction_data = LoanInstruction::InitializeLoan { amount, apy }.try_to_vec().unwrap();
-
-        // Mock Rent and Clock sysvars
-        let rent = Rent {
-            lamports_per_byte_year: 1,
-            exemption_threshold: 2.0,
-            burn_percent: 5,
+        let (loan_account_key, _) = Pubkey::find_program_address(&[borrower_key.as_ref(), b"loan"], &program_id);
+        let (authority_key, _) = Pubkey::find_program_address(&[b"authority"], &program_id);
+        let (blocklist_marker_key, _) = blocklist::marker_address(&program_id, &borrower_key);
+        let (stats_key, _) = Pubkey::find_program_address(&[b"stats"], &program_id);
+        let (bad_debt_key, _) = Pubkey::find_program_address(&[borrower_key.as_ref(), b"bad_debt"], &program_id);
+        let system_program_id = system_program::id();
+        let token_program_id = spl_token::id();
+        let rent = Rent::default();
+        let start_date = 1625097600;
+
+        // CPIs do not run outside the runtime, so the loan account is passed as
+        // the create_account CPI leaves it and the transfers are not observed
+        let mut input = serialize_input(
+            &program_id,
+            &[
+                test_account(borrower_key, true, 10_000_000_000, vec![], system_program_id), // 10 SOL
+                test_account(loan_account_key, false, rent.minimum_balance(LOAN_ACCOUNT_LEN), vec![0; LOAN_ACCOUNT_LEN], program_id),
+                test_account(Pubkey::new_unique(), false, 0, usdc_account_data(&borrower_key, 0), token_program_id),
+                test_account(PROGRAM_USDC_ACCOUNT, false, 0, usdc_account_data(&authority_key, 1_000_000_000), token_program_id),
+                test_account(TREASURY_USDC_ACCOUNT, false, 0, usdc_account_data(&Pubkey::new_unique(), 0), token_program_id),
+                test_account(system_program_id, false, 0, vec![], system_program_id),
+                test_account(token_program_id, false, 0, vec![], system_program_id),
+                test_account(authority_key, false, 0, vec![], system_program_id),
+                test_account(solana_program::sysvar::rent::id(), false, 0, rent_sysvar_data(&rent), system_program_id),
+                test_account(solana_program::sysvar::clock::id(), false, 0, clock_sysvar_data(start_date), system_program_id),
+                test_account(blocklist_marker_key, false, 0, vec![], system_program_id),
+                test_account(stats_key, false, 0, Stats::default().try_to_vec().unwrap(), program_id),
+                test_account(bad_debt_key, false, 0, vec![], system_program_id),
+            ],
+            &LoanInstruction::InitializeLoan { amount: 100_000_000, apy: 5, max_collateral: None, deadline: None }
+                .try_to_vec()
+                .unwrap(),
+        );
+        let (program_id, accounts, instruction_data) = unsafe { entrypoint::deserialize(input.as_mut_ptr() as *mut u8) };
+
+        process_instruction(program_id, &accounts, instruction_data).unwrap();
+
+        // The origination fee is deducted from the disbursement, so the
+        // principal is the amount requested
+        let loan_data = LoanAccount::try_from_slice(&accounts[1].data.borrow()).unwrap();
+        let expected_collateral = required_collateral(100_000_000).unwrap();
+        assert_eq!(loan_data.borrower, borrower_key);
+        assert_eq!(loan_data.principal, 100_000_000);
+        assert_eq!(loan_data.apy, 5);
+        assert_eq!(loan_data.collateral, expected_collateral);
+        assert_eq!(loan_data.start_date, start_date);
+        assert_eq!(loan_data.last_accrual, start_date);
+
+        let stats = Stats::try_from_slice(&accounts[11].data.borrow()).unwrap();
+        assert_eq!(stats.loans_originated, 1);
+        assert_eq!(stats.usdc_borrowed, 100_000_000);
+        assert_eq!(stats.sol_collateral_locked, expected_collateral);
+    }
+
+    #[test]
+    fn test_repay_loan() {
+        let program_id = Pubkey::new_unique();
+        let borrower_key = Pubkey::new_unique();
+        let (loan_account_key, _) = Pubkey::find_program_address(&[borrower_key.as_ref(), b"loan"], &program_id);
+        let (authority_key, _) = Pubkey::find_program_address(&[b"authority"], &program_id);
+        let (stats_key, _) = Pubkey::find_program_address(&[b"stats"], &program_id);
+        let shard = loan_archive_shard(&borrower_key);
+        let (loan_archive_key, _) = Pubkey::find_program_address(&[b"loan_archive", &[shard]], &program_id);
+        let system_program_id = system_program::id();
+        let token_program_id = spl_token::id();
+        // The loan account holds the collateral on top of its own rent
+        let loan_lamports = 100_000_000 + Rent::default().minimum_balance(LOAN_ACCOUNT_LEN);
+
+        let loan_data = LoanAccount {
+            borrower: borrower_key,
+            start_date: 1625097600,
+            principal: 100_000_000, // 100 USDC
+            apy: 5,
+            collateral: 100_000_000, // 0.1 SOL
+            interest_owed: 0,
+            fees_owed: 0,
+            last_accrual: 1625097600,
         };
-        let clock = Clock {
-            slot: 0,
-            epoch_start_timestamp: 0,
-            epoch: 0,
-            leader_schedule_epoch: 0,
-            unix_timestamp: 1625097600, // Example timestamp
+        let stats = Stats {
+            sol_collateral_locked: 100_000_000,
+            ..Stats::default()
         };
 
-        // Override the Rent and Clock account data
-        accounts[6].data = rent.try_to_vec().unwrap().into();
-        accounts[7].data = clock.try_to_vec().unwrap().into();
+        let mut input = serialize_input(
+            &program_id,
+            &[
+                test_account(borrower_key, true, 900_000_000, vec![], system_program_id),
+                test_account(loan_account_key, false, loan_lamports, loan_data.try_to_vec().unwrap(), program_id),
+                test_account(Pubkey::new_unique(), false, 0, usdc_account_data(&borrower_key, 200_000_000), token_program_id),
+                test_account(PROGRAM_USDC_ACCOUNT, false, 0, usdc_account_data(&authority_key, 900_000_000), token_program_id),
+                test_account(token_program_id, false, 0, vec![], system_program_id),
+                test_account(solana_program::sysvar::clock::id(), false, 0, clock_sysvar_data(1625184000), system_program_id), // 1 day later
+                test_account(stats_key, false, 0, stats.try_to_vec().unwrap(), program_id),
+                test_account(loan_archive_key, false, 0, vec![0; LOAN_ARCHIVE_LEN], program_id),
+            ],
+            // More than is due: the waterfall only takes principal plus a day of interest
+            &LoanInstruction::RepayLoan { amount: 105_000_000, deadline: None }.try_to_vec().unwrap(),
+        );
+        let (program_id, accounts, instruction_data) = unsafe { entrypoint::deserialize(input.as_mut_ptr() as *mut u8) };
+
+        process_instruction(program_id, &accounts, instruction_data).unwrap();
+
+        // Collateral and rent returned, leaving the loan account empty
+        assert_eq!(accounts[0].lamports(), 900_000_000 + loan_lamports);
+        assert_eq!(accounts[1].lamports(), 0);
+        assert_eq!(accounts[1].data_len(), 0);
+        assert_eq!(*accounts[1].owner, system_program_id);
+
+        let stats = Stats::try_from_slice(&accounts[6].data.borrow()).unwrap();
+        assert_eq!(stats.usdc_interest_paid, 13_699);
+        assert_eq!(stats.sol_collateral_locked, 0);
+
+        let data = accounts[7].data.borrow();
+        let record = ClosedLoan::try_from_slice(&data[LOAN_ARCHIVE_HEADER_LEN..LOAN_ARCHIVE_HEADER_LEN + CLOSED_LOAN_LEN]).unwrap();
+        assert!(!record.liquidated);
+        assert_eq!(record.final_payment, 100_013_699);
+        assert_eq!(record.collateral, 100_000_000);
+    }
 
-        // Process the instruction
-        process_instruction(&program_id, &accounts, &instruction_data).unwrap();
+    #[test]
+    fn test_liquidation_preview() {
+        // 0.1 SOL, worth 15 USDC, against 100 USDC borrowed
+        let mut loan_data = LoanAccount {
+            borrower: Pubkey::new_unique(),
+            start_date: 1625097600,
+            principal: 100_000_000,
+            apy: 5,
+            collateral: 100_000_000,
+            interest_owed: 0,
+            fees_owed: 0,
+            last_accrual: 1625097600,
+        };
 
-        // Verify the loan account was created and initialized correctly
-        let loan_data = LoanAccount::try_from_slice(&loan_account.data.borrow()).unwrap();
-        assert_eq!(loan_data.borrower, borrower_key);
-        assert_eq!(loan_data.principal, amount);
-        assert_eq!(loan_data.apy, apy);
-        assert_eq!(loan_data.start_date, clock.unix_timestamp);
+        // Two days later, as in test_liquidate_loan
+        let preview = liquidation_preview(&loan_data.try_to_vec().unwrap(), 1625270400).unwrap();
+        assert_eq!(preview, LiquidationPreview {
+            eligible: true,
+            repay_amount: 14_250_000,
+            collateral_received: 100_000_000,
+            expected_profit: 750_000,
+        });
+
+        // 1 SOL is worth 150 USDC, more than the 100 USDC due
+        loan_data.collateral = 1_000_000_000;
+        let preview = liquidation_preview(&loan_data.try_to_vec().unwrap(), 1625270400).unwrap();
+        assert_eq!(preview, LiquidationPreview::default());
+    }
 
-        // Verify the collateral was transferred
-        let expected_collateral = (amount * 100) / (SOL_PRICE * LTV);
-        assert_eq!(loan_data.collateral, expected_collateral);
-        assert_eq!(borrower_account.lamports(), 1000000000 - expected_collateral);
-        assert_eq!(loan_account.lamports(), expected_collateral);
+    #[test]
+    fn test_liquidate_loan() {
+        let program_id = Pubkey::new_unique();
+        let borrower_key = Pubkey::new_unique();
+        let liquidator_key = Pubkey::new_unique();
+        let (loan_account_key, _) = Pubkey::find_program_address(&[borrower_key.as_ref(), b"loan"], &program_id);
+        let (authority_key, _) = Pubkey::find_program_address(&[b"authority"], &program_id);
+        let (stats_key, _) = Pubkey::find_program_address(&[b"stats"], &program_id);
+        let shard = loan_archive_shard(&borrower_key);
+        let (loan_archive_key, _) = Pubkey::find_program_address(&[b"loan_archive", &[shard]], &program_id);
+        let (bad_debt_key, _) = Pubkey::find_program_address(&[borrower_key.as_ref(), b"bad_debt"], &program_id);
+        let system_program_id = system_program::id();
+        let token_program_id = spl_token::id();
+        let loan_lamports = 100_000_000 + Rent::default().minimum_balance(LOAN_ACCOUNT_LEN);
+
+        // 0.1 SOL, worth 15 USDC at the program price, against 100 USDC borrowed
+        let loan_data = LoanAccount {
+            borrower: borrower_key,
+            start_date: 1625097600,
+            principal: 100_000_000,
+            apy: 5,
+            collateral: 100_000_000,
+            interest_owed: 0,
+            fees_owed: 0,
+            last_accrual: 1625097600,
+        };
+        let stats = Stats {
+            sol_collateral_locked: 100_000_000,
+            ..Stats::default()
+        };
 
-        // In a real test, we would also verify the USDC transfer, but we've mocked the token accounts here
+        // Bad debt left by an earlier loan, so no account has to be created
+        let bad_debt = BadDebt {
+            borrower: borrower_key,
+            market: LoanMarket::Usdc,
+            amount: 1_000_000,
+            recorded_at: 1625000000,
+            funder: Pubkey::new_unique(),
+        };
+
+        let mut input = serialize_input(
+            &program_id,
+            &[
+                test_account(liquidator_key, true, 1_000_000_000, vec![], system_program_id),
+                test_account(loan_account_key, false, loan_lamports, loan_data.try_to_vec().unwrap(), program_id),
+                test_account(Pubkey::new_unique(), false, 0, usdc_account_data(&liquidator_key, 1_000_000_000), token_program_id),
+                test_account(PROGRAM_USDC_ACCOUNT, false, 0, usdc_account_data(&authority_key, 900_000_000), token_program_id),
+                test_account(token_program_id, false, 0, vec![], system_program_id),
+                test_account(solana_program::sysvar::clock::id(), false, 0, clock_sysvar_data(1625270400), system_program_id), // 2 days later
+                test_account(stats_key, false, 0, stats.try_to_vec().unwrap(), program_id),
+                test_account(loan_archive_key, false, 0, vec![0; LOAN_ARCHIVE_LEN], program_id),
+                test_account(bad_debt_key, false, 0, bad_debt.try_to_vec().unwrap(), program_id),
+                test_account(system_program_id, false, 0, vec![], system_program_id),
+            ],
+            &LoanInstruction::LiquidateLoan { deadline: None }.try_to_vec().unwrap(),
+        );
+        let (program_id, accounts, instruction_data) = unsafe { entrypoint::deserialize(input.as_mut_ptr() as *mut u8) };
+
+        process_instruction(program_id, &accounts, instruction_data).unwrap();
+
+        // The liquidator receives the collateral and the loan account's rent
+        assert_eq!(accounts[0].lamports(), 1_000_000_000 + loan_lamports);
+        assert_eq!(accounts[1].lamports(), 0);
+        assert_eq!(accounts[1].data_len(), 0);
+
+        // The collateral is worth 15 USDC, so the liquidator pays 14.25 USDC:
+        // 27_398 of interest and 14_222_602 of principal. The other 85_777_398
+        // due is added to the bad debt
+        let bad_debt = BadDebt::try_from_slice(&accounts[8].data.borrow()).unwrap();
+        assert_eq!(bad_debt.amount, 86_777_398);
+        assert_eq!(bad_debt.recorded_at, 1625270400);
+
+        let stats = Stats::try_from_slice(&accounts[6].data.borrow()).unwrap();
+        assert_eq!(stats.liquidations, 1);
+        assert_eq!(stats.usdc_interest_paid, 27_398);
+        assert_eq!(stats.sol_collateral_locked, 0);
+        assert_eq!(stats.usdc_bad_debt_recorded, 85_777_398);
+
+        let data = accounts[7].data.borrow();
+        let record = ClosedLoan::try_from_slice(&data[LOAN_ARCHIVE_HEADER_LEN..LOAN_ARCHIVE_HEADER_LEN + CLOSED_LOAN_LEN]).unwrap();
+        assert!(record.liquidated);
+        assert_eq!(record.final_payment, 14_250_000);
     }
 
     #[test]
-    fn test_repay_loan() {
-        // Similar setup to test_initialize_loan
+    fn test_repay_sol_loan_forgives_dust() {
         let program_id = Pubkey::new_unique();
         let borrower_key = Pubkey::new_unique();
-        let loan_account_key = Pubkey::new_unique();
-        let usdc_mint_key = Pubkey::new_unique();
-        let borrower_usdc_account_key = Pubkey::new_unique();
-        let program_usdc_account_key = Pubkey::new_unique();
+        let (loan_account_key, _) = Pubkey::find_program_address(&[borrower_key.as_ref(), b"sol_loan"], &program_id);
+        let (collateral_vault_key, _) = Pubkey::find_program_address(&[b"usdc_collateral_vault"], &program_id);
+        let (sol_reserve_key, _) = Pubkey::find_program_address(&[b"sol_reserve"], &program_id);
+        let (authority_key, _) = Pubkey::find_program_address(&[b"authority"], &program_id);
+        let (stats_key, _) = Pubkey::find_program_address(&[b"stats"], &program_id);
+        let shard = loan_archive_shard(&borrower_key);
+        let (loan_archive_key, _) = Pubkey::find_program_address(&[b"loan_archive", &[shard]], &program_id);
+        let system_program_id = system_program::id();
+        let token_program_id = spl_token::id();
+        let loan_lamports = Rent::default().minimum_balance(LOAN_ACCOUNT_LEN);
+        let now = 1625097600;
+
+        let loan_data = LoanAccount {
+            borrower: borrower_key,
+            start_date: now,
+            principal: 1_000_000_000, // 1 SOL
+            apy: 5,
+            collateral: 300_000_000, // 300 USDC
+            interest_owed: 0,
+            fees_owed: 0,
+            last_accrual: now,
+        };
+        let stats = Stats {
+            usdc_collateral_locked: 300_000_000,
+            ..Stats::default()
+        };
 
-        let mut borrower_lamports = 900000000; // 9 SOL (after collateral deposit)
-        let mut loan_account_lamports = 100000000; // 1 SOL collateral
-        let mut borrower_usdc_lamports = 1100000000; // 1100 USDC (after loan)
-        let mut program_usdc_lamports = 900000000; // 900 USDC (after loan)
+        let mut input = serialize_input(
+            &program_id,
+            &[
+                test_account(borrower_key, true, 2_000_000_000, vec![], system_program_id),
+                test_account(loan_account_key, false, loan_lamports, loan_data.try_to_vec().unwrap(), program_id),
+                test_account(Pubkey::new_unique(), false, 0, usdc_account_data(&borrower_key, 0), token_program_id),
+                test_account(collateral_vault_key, false, 0, usdc_account_data(&authority_key, 300_000_000), token_program_id),
+                test_account(sol_reserve_key, false, 0, vec![], program_id),
+                test_account(system_program_id, false, 0, vec![], system_program_id),
+                test_account(token_program_id, false, 0, vec![], system_program_id),
+                test_account(authority_key, false, 0, vec![], system_program_id),
+                test_account(solana_program::sysvar::clock::id(), false, 0, clock_sysvar_data(now), system_program_id),
+                test_account(stats_key, false, 0, stats.try_to_vec().unwrap(), program_id),
+                test_account(loan_archive_key, false, 0, vec![0; LOAN_ARCHIVE_LEN], program_id),
+            ],
+            // Leaves 50_000 lamports, under SOL_DUST_THRESHOLD
+            &LoanInstruction::RepaySolLoan { amount: 999_950_000, deadline: None }.try_to_vec().unwrap(),
+        );
+        let (program_id, accounts, instruction_data) = unsafe { entrypoint::deserialize(input.as_mut_ptr() as *mut u8) };
+
+        process_instruction(program_id, &accounts, instruction_data).unwrap();
+
+        // The dust is forgiven, so the loan closes and its rent is refunded
+        assert_eq!(accounts[0].lamports(), 2_000_000_000 + loan_lamports);
+        assert_eq!(accounts[1].lamports(), 0);
+        assert_eq!(accounts[1].data_len(), 0);
+
+        let stats = Stats::try_from_slice(&accounts[9].data.borrow()).unwrap();
+        assert_eq!(stats.usdc_collateral_locked, 0);
+
+        let data = accounts[10].data.borrow();
+        let record = ClosedLoan::try_from_slice(&data[LOAN_ARCHIVE_HEADER_LEN..LOAN_ARCHIVE_HEADER_LEN + CLOSED_LOAN_LEN]).unwrap();
+        assert_eq!(record.market, LoanMarket::Sol);
+        assert_eq!(record.final_payment, 999_950_000);
+    }
 
-        let mut loan_account_data = LoanAccount {
-            borrower: borrower_key,
-            start_date: 1625097600, // Example start timestamp
-            principal: 100000000, // 100 USDC
-            apy: 500, // 5% APY
-            collateral: 100000000, // 1 SOL
-        }.try_to_vec().unwrap();
-
-        let mut borrower_usdc_data = vec![0; 165]; // Mocked SPL Token account data
-        let mut program_usdc_data = vec![0; 165]; // Mocked SPL Token account data
-
-        let borrower_account = create_account_info(&borrower_key, true, &mut borrower_lamports, &mut [], &program_id);
-        let loan_account = create_account_info(&loan_account_key, false, &mut loan_account_lamports, &mut loan_account_data, &program_id);
-        let borrower_usdc_account = create_account_info(&borrower_usdc_account_key, false, &mut borrower_usdc_lamports, &mut borrower_usdc_data, &usdc_mint_key);
-        let program_usdc_account = create_account_info(&program_usdc_account_key, false, &mut program_usdc_lamports, &mut program_usdc_data, &usdc_mint_key);
-
-        let token_program_key = Pubkey::new_unique();
-        let clock_key = Pubkey::new_unique();
-
-        let accounts = vec![
-            borrower_account,
-            loan_account,
-            borrower_usdc_account,
-            program_usdc_account,
-            create_account_info(&token_program_key, false, &mut 0, &mut [], &program_id),
-            create_account_info(&clock_key, false, &mut 0, &mut [], &program_id),
-        ];
+    #[test]
+    fn test_rollover_capitalizes_interest() {
+        let start_date = 1625097600;
+        let mut loan_data = LoanAccount {
+            borrower: Pubkey::new_unique(),
+            start_date,
+            principal: 100_000_000, // 100 USDC
+            apy: 5,
+            collateral: 3_000_000_000, // 3 SOL
+            interest_owed: 0,
+            fees_owed: 0,
+            last_accrual: start_date,
+        };
 
-        let repay_amount = 105000000; // 105 USDC (principal + interest)
+        let one_year_later = start_date + 365 * 24 * 60 * 60;
+        let interest = rollover(&mut loan_data, one_year_later).unwrap();
 
-        let instruction_data = LoanInstruction::RepayLoan { amount: repay_amount }.try_to_vec().unwrap();
+        assert_eq!(interest, 5_000_000);
+        assert_eq!(loan_data.principal, 105_000_000);
+        assert_eq!(loan_data.start_date, one_year_later);
+        assert_eq!(accrued_interest(&loan_data, one_year_later).unwrap(), 0);
+    }
 
-        // Mock Clock sysvar
-        let clock = Clock {
-            slot: 0,
-            epoch_start_timestamp: 0,
-            epoch: 0,
-            leader_schedule_epoch: 0,
-            unix_timestamp: 1625184000, // 1 day later
+    #[test]
+    fn test_interest_accrual_over_days_and_months() {
+        const DAY: i64 = 24 * 60 * 60;
+        let start_date = 1625097600;
+        let mut loan_data = LoanAccount {
+            borrower: Pubkey::new_unique(),
+            start_date,
+            principal: 1_000_000_000, // 1000 USDC
+            apy: 10,
+            collateral: required_collateral(1_000_000_000).unwrap() * 2,
+            interest_owed: 0,
+            fees_owed: 0,
+            last_accrual: start_date,
         };
 
-        // Override the Clock account data
-        accounts[5].data = clock.try_to_vec().unwrap().into();
+        assert_eq!(accrued_interest(&loan_data, start_date + DAY).unwrap(), 273_973);
+        assert_eq!(accrued_interest(&loan_data, start_date + 30 * DAY).unwrap(), 8_219_179);
+        assert_eq!(accrued_interest(&loan_data, start_date + 365 * DAY).unwrap(), 100_000_000);
+
+        // Accruing daily then monthly lands on the same amount as one 30 day accrual
+        accrue(&mut loan_data, start_date + DAY).unwrap();
+        assert_eq!(loan_data.interest_owed, 273_973);
+        accrue(&mut loan_data, start_date + 30 * DAY).unwrap();
+        assert_eq!(loan_data.interest_owed, 8_219_179);
+
+        // A partial payment clears the interest before touching principal
+        let breakdown = apply_repayment(&mut loan_data, 10_000_000);
+        assert_eq!(breakdown.interest_paid, 8_219_179);
+        assert_eq!(breakdown.principal_paid, 1_780_821);
+        assert_eq!(loan_data.principal, 998_219_179);
+
+        // Rolling over a month later capitalizes interest on the reduced principal
+        let interest = rollover(&mut loan_data, start_date + 60 * DAY).unwrap();
+        assert_eq!(interest, 8_204_542);
+        assert_eq!(loan_data.principal, 1_006_423_721);
+        assert_eq!(loan_data.interest_owed, 0);
+    }
 
-        // Process the instruction
-        process_instruction(&program_id, &accounts, &instruction_data).unwrap();
+    // Golden vectors, checked against exact rational arithmetic. Collateral is
+    // the lamports needed to borrow `amount` base units of a token with
+    // `decimals` decimals at a whole-dollar SOL `price` and `ltv` percent.
+    // Interest is simple interest on `principal` at `apy` percent over
+    // `seconds`, with a 365 day year.
+
+    // (price, amount, ltv, decimals, required collateral)
+    const COLLATERAL_VECTORS: &[(u64, u64, u64, u8, u64)] = &[
+        (150, 1_000_000, 25, 6, 26_666_667),
+        (150, 1_000_000_000, 25, 6, 26_666_666_667),
+        (150, 1, 25, 6, 27),
+        (150, 999_999, 25, 6, 26_666_640),
+        (100, 1_000_000, 50, 6, 20_000_000),
+        (37, 123_456_789, 33, 6, 10_111_121_131),
+        (20, 5_000_000, 80, 6, 312_500_000),
+        (1000, 1_000_000, 75, 6, 1_333_334),
+        (150, 10_000_000, 25, 6, 266_666_667),
+        (150, 1_000_000_000_000, 25, 6, 26_666_666_666_667),
+        (1, 1_000_000, 1, 6, 100_000_000_000),
+        (250, 7, 60, 6, 47),
+        (150, 1_000_000_000, 25, 9, 26_666_667),
+        (150, 1_000_000, 25, 0, 26_666_666_666_667),
+        (3, 2, 90, 2, 7_407_408),
+        (9999, 100_000_000, 50, 6, 20_002_001),
+    ];
+
+    // (principal, apy, seconds, interest)
+    const INTEREST_VECTORS: &[(u64, u64, u64, u64)] = &[
+        (1_000_000_000, 10, 86_400, 273_973),
+        (1_000_000_000, 10, 2_592_000, 8_219_179),
+        (1_000_000_000, 10, 31_536_000, 100_000_000),
+        (100_000_000, 5, 31_536_000, 5_000_000),
+        (1, 1, 1, 1),
+        (0, 50, 31_536_000, 0),
+        (1_000_000, 100, 31_536_000, 1_000_000),
+        (123_456_789, 7, 12_345, 3_383),
+        (10_000_000, 3, 3_600, 35),
+        (5_000_000_000_000, 20, 63_072_000, 2_000_000_000_000),
+        (1_000_000, 1, 60, 1),
+        (999_999_999, 15, 7_776_000, 36_986_302),
+    ];
 
-        // Verify the loan was repaid
-        assert_eq!(borrower_account.lamports(), 1000000000); // 10 SOL (collateral returned)
-        assert_eq!(loan_account.lamports(), 0);
-        
-        // In a real test, we would also verify the USDC transfer, but we've mocked the token accounts here
+    #[test]
+    fn test_collateral_golden_vectors() {
+        for &(price, amount, ltv, decimals, expected) in COLLATERAL_VECTORS {
+            let collateral = collateral_for(amount, decimals, price, ltv);
+            assert_eq!(collateral, Ok(expected), "price {} amount {} ltv {} decimals {}", price, amount, ltv, decimals);
+
+            // Vectors at the program's own parameters also hold for the loan
+            // math, and the collateral is worth at least the loan over the LTV
+            if (price, ltv, decimals) == (SOL_PRICE, LTV, USDC_DECIMALS) {
+                assert_eq!(required_collateral(amount), Ok(expected), "amount {}", amount);
+                assert!(collateral_value(expected).unwrap() as u128 * LTV as u128 >= amount as u128 * 100, "amount {}", amount);
+            }
+        }
     }
 
     #[test]
-    fn test_liquidate_loan() {
-        // Similar setup to previous tests
-        let program_id = Pubkey::new_unique();
+    fn test_interest_golden_vectors() {
+        let start_date = 1625097600;
+        for &(principal, apy, seconds, expected) in INTEREST_VECTORS {
+            let loan_data = LoanAccount {
+                borrower: Pubkey::new_unique(),
+                start_date,
+                principal,
+                apy,
+                collateral: 0,
+                interest_owed: 0,
+                fees_owed: 0,
+                last_accrual: start_date,
+            };
+            let interest = accrued_interest(&loan_data, start_date + seconds as i64);
+            assert_eq!(interest, Ok(expected), "principal {} apy {} seconds {}", principal, apy, seconds);
+        }
+    }
+
+    #[test]
+    fn test_rollover_rejects_undercollateralized_loan() {
+        let start_date = 1625097600;
+        let mut loan_data = LoanAccount {
+            borrower: Pubkey::new_unique(),
+            start_date,
+            principal: 100_000_000, // 100 USDC
+            apy: 5,
+            collateral: required_collateral(100_000_000).unwrap(),
+            interest_owed: 0,
+            fees_owed: 0,
+            last_accrual: start_date,
+        };
+
+        let result = rollover(&mut loan_data, start_date + 365 * 24 * 60 * 60);
+        assert_eq!(result, Err(LoanError::InsufficientCollateral.into()));
+        assert_eq!(loan_data.principal, 100_000_000);
+        assert_eq!(loan_data.start_date, start_date);
+    }
+
+    #[test]
+    fn test_repayment_waterfall_pays_fees_then_interest_then_principal() {
+        let start_date = 1625097600;
+        let mut loan_data = LoanAccount {
+            borrower: Pubkey::new_unique(),
+            start_date,
+            principal: 100_000_000, // 100 USDC
+            apy: 5,
+            collateral: 100_000_000,
+            interest_owed: 0,
+            fees_owed: 1_000_000, // 1 USDC
+            last_accrual: start_date,
+        };
+        accrue(&mut loan_data, start_date + 365 * 24 * 60 * 60).unwrap();
+        assert_eq!(loan_data.interest_owed, 5_000_000);
+
+        // Covers the fee and part of the interest, nothing reaches principal
+        let breakdown = apply_repayment(&mut loan_data, 3_000_000);
+        assert_eq!(breakdown, RepaymentBreakdown { fees_paid: 1_000_000, interest_paid: 2_000_000, principal_paid: 0 });
+        assert_eq!(loan_data.fees_owed, 0);
+        assert_eq!(loan_data.interest_owed, 3_000_000);
+        assert_eq!(loan_data.principal, 100_000_000);
+
+        // Clears the remaining interest and part of the principal
+        let breakdown = apply_repayment(&mut loan_data, 53_000_000);
+        assert_eq!(breakdown, RepaymentBreakdown { fees_paid: 0, interest_paid: 3_000_000, principal_paid: 50_000_000 });
+        assert_eq!(loan_data.principal, 50_000_000);
+        assert_eq!(total_due(&loan_data).unwrap(), 50_000_000);
+    }
+
+    #[test]
+    fn test_repayment_waterfall_caps_overpayment() {
+        let start_date = 1625097600;
+        let mut loan_data = LoanAccount {
+            borrower: Pubkey::new_unique(),
+            start_date,
+            principal: 100_000_000, // 100 USDC
+            apy: 5,
+            collateral: 100_000_000,
+            interest_owed: 5_000_000,
+            fees_owed: 1_000_000,
+            last_accrual: start_date,
+        };
+
+        let breakdown = apply_repayment(&mut loan_data, 200_000_000);
+        assert_eq!(breakdown.total(), 106_000_000);
+        assert_eq!(total_due(&loan_data).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_origination_fee_deducted_from_disbursement() {
+        let terms = loan_terms(100_000_000, OriginationFeeMode::DeductFromDisbursement).unwrap();
+        assert_eq!(terms, LoanTerms { principal: 100_000_000, disbursed: 99_500_000, origination_fee: 500_000 });
+    }
+
+    #[test]
+    fn test_origination_fee_added_to_principal() {
+        let terms = loan_terms(100_000_000, OriginationFeeMode::AddToPrincipal).unwrap();
+        assert_eq!(terms, LoanTerms { principal: 100_500_000, disbursed: 100_000_000, origination_fee: 500_000 });
+    }
+
+    #[test]
+    fn test_prepayment_fee_schedule() {
+        const DAY: i64 = 24 * 60 * 60;
+        let schedule = [(30 * DAY, 200), (90 * DAY, 100)];
+        let start_date = 1625097600;
+        let loan_data = LoanAccount {
+            borrower: Pubkey::new_unique(),
+            start_date,
+            principal: 100_000_000, // 100 USDC
+            apy: 5,
+            collateral: 100_000_000,
+            interest_owed: 0,
+            fees_owed: 0,
+            last_accrual: start_date,
+        };
+
+        // 2% tier: 51 USDC retires 50 USDC of principal plus a 1 USDC fee
+        let fee = prepayment_fee(&loan_data, 51_000_000, start_date + 10 * DAY, &schedule).unwrap();
+        assert_eq!(fee, 1_000_000);
+
+        // 1% tier, fee capped to the outstanding principal
+        let fee = prepayment_fee(&loan_data, 500_000_000, start_date + 60 * DAY, &schedule).unwrap();
+        assert_eq!(fee, 1_000_000);
+
+        // Past the last tier, and with no schedule at all, there is no fee
+        assert_eq!(prepayment_fee(&loan_data, 51_000_000, start_date + 90 * DAY, &schedule).unwrap(), 0);
+        assert_eq!(prepayment_fee(&loan_data, 51_000_000, start_date, &[]).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_sol_loan_early_repayment_pays_prepayment_fee() {
+        const DAY: i64 = 24 * 60 * 60;
+        let schedule = [(30 * DAY, 200)];
+        let start_date = 1625097600;
+        let mut loan_data = LoanAccount {
+            borrower: Pubkey::new_unique(),
+            start_date,
+            principal: 1_000_000_000, // 1 SOL
+            apy: 0,
+            collateral: 300_000_000, // 300 USDC
+            interest_owed: 0,
+            fees_owed: 0,
+            last_accrual: start_date,
+        };
+
+        // 0.51 SOL in the 2% tier pays a 0.01 SOL fee and 0.5 SOL of principal
+        let breakdown = apply_loan_repayment(&mut loan_data, 510_000_000, start_date + 10 * DAY, &schedule).unwrap();
+        assert_eq!(breakdown, RepaymentBreakdown { fees_paid: 10_000_000, interest_paid: 0, principal_paid: 500_000_000 });
+        assert_eq!(loan_data.principal, 500_000_000);
+        assert_eq!(loan_data.fees_owed, 0);
+    }
+
+    #[test]
+    fn test_forgive_dust() {
+        let start_date = 1625097600;
+        let mut loan_data = LoanAccount {
+            borrower: Pubkey::new_unique(),
+            start_date,
+            principal: 9_000,
+            apy: 5,
+            collateral: 100_000_000,
+            interest_owed: 500,
+            fees_owed: 0,
+            last_accrual: start_date,
+        };
+
+        assert_eq!(forgive_dust(&mut loan_data, 9_000).unwrap(), 0);
+        assert_eq!(total_due(&loan_data).unwrap(), 9_500);
+
+        assert_eq!(forgive_dust(&mut loan_data, DUST_THRESHOLD).unwrap(), 9_500);
+        assert_eq!(total_due(&loan_data).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_rounding_favors_protocol() {
+        // 100 USDC needs 2_666_666_666.67 lamports, so one more lamport is locked
+        assert_eq!(required_collateral(100_000_000).unwrap(), 2_666_666_667);
+        // ..while that collateral is valued at the truncated 400_000_000.05
+        assert_eq!(collateral_value(2_666_666_667).unwrap(), 400_000_000);
+
+        // One second of interest on 1 USDC at 5% is far below a base unit but still owed
+        let start_date = 1625097600;
+        let loan_data = LoanAccount {
+            borrower: Pubkey::new_unique(),
+            start_date,
+            principal: 1_000_000,
+            apy: 5,
+            collateral: 100_000_000,
+            interest_owed: 0,
+            fees_owed: 0,
+            last_accrual: start_date,
+        };
+        assert_eq!(accrued_interest(&loan_data, start_date + 1).unwrap(), 1);
+        assert_eq!(accrued_interest(&loan_data, start_date).unwrap(), 0);
+
+        // Fees round up
+        assert_eq!(loan_terms(1_000_001, OriginationFeeMode::AddToPrincipal).unwrap().origination_fee, 5_001);
+    }
+
+    #[test]
+    fn test_loan_account_layout_offsets() {
+        let loan_data = LoanAccount {
+            borrower: Pubkey::new_unique(),
+            start_date: 1,
+            principal: 2,
+            apy: 3,
+            collateral: 4,
+            interest_owed: 5,
+            fees_owed: 6,
+            last_accrual: 7,
+        };
+        let data = loan_data.try_to_vec().unwrap();
+        let read_u64 = |offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+
+        assert_eq!(data.len(), LOAN_ACCOUNT_LEN);
+        assert_eq!(data.len(), mem::size_of::<LoanAccount>());
+        assert_eq!(&data[LOAN_BORROWER_OFFSET..LOAN_BORROWER_OFFSET + 32], loan_data.borrower.as_ref());
+        assert_eq!(read_u64(LOAN_START_DATE_OFFSET), 1);
+        assert_eq!(read_u64(LOAN_PRINCIPAL_OFFSET), 2);
+        assert_eq!(read_u64(LOAN_APY_OFFSET), 3);
+        assert_eq!(read_u64(LOAN_COLLATERAL_OFFSET), 4);
+        assert_eq!(read_u64(LOAN_INTEREST_OWED_OFFSET), 5);
+        assert_eq!(read_u64(LOAN_FEES_OWED_OFFSET), 6);
+        assert_eq!(read_u64(LOAN_LAST_ACCRUAL_OFFSET), 7);
+    }
+
+    #[test]
+    fn test_parse_legacy_loan_account() {
+        let borrower = Pubkey::new_unique();
+        let mut data = borrower.to_bytes().to_vec();
+        for value in [1625097600u64, 100_000_000, 5, 600_000_000] {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        assert_eq!(data.len(), LEGACY_LOAN_ACCOUNT_LEN);
+
+        let loan_data = parse_loan_account(&data).unwrap();
+        assert_eq!(loan_data.borrower, borrower);
+        assert_eq!(loan_data.start_date, 1625097600);
+        assert_eq!(loan_data.principal, 100_000_000);
+        assert_eq!(loan_data.apy, 5);
+        assert_eq!(loan_data.collateral, 600_000_000);
+        assert_eq!(loan_data.interest_owed, 0);
+        assert_eq!(loan_data.fees_owed, 0);
+        assert_eq!(loan_data.last_accrual, 1625097600);
+
+        // The current layout round-trips, other lengths are rejected
+        let current = loan_data.try_to_vec().unwrap();
+        assert_eq!(parse_loan_account(&current).unwrap().last_accrual, 1625097600);
+        assert!(parse_loan_account(&data[..LEGACY_LOAN_ACCOUNT_LEN - 1]).is_err());
+    }
+
+    #[test]
+    fn test_closed_loan_record_len() {
+        let record = ClosedLoan {
+            borrower: Pubkey::new_unique(),
+            market: LoanMarket::Sol,
+            liquidated: true,
+            start_date: 1625097600,
+            closed_at: 1625097600 + 86400,
+            collateral: 600_000_000,
+            final_payment: 1_000_000_000,
+        };
+        let bytes = record.try_to_vec().unwrap();
+        assert_eq!(bytes.len(), CLOSED_LOAN_LEN);
+        assert_eq!(&bytes[..32], record.borrower.as_ref());
+        assert_eq!(ClosedLoan::try_from_slice(&bytes).unwrap(), record);
+    }
+
+    #[test]
+    fn test_bad_debt_record_len() {
+        let record = BadDebt {
+            borrower: Pubkey::new_unique(),
+            market: LoanMarket::Usdc,
+            amount: 250_000_000,
+            recorded_at: 1625097600,
+            funder: Pubkey::new_unique(),
+        };
+        let bytes = record.try_to_vec().unwrap();
+        assert_eq!(bytes.len(), BAD_DEBT_LEN);
+        assert_eq!(BadDebt::try_from_slice(&bytes).unwrap(), record);
+    }
+
+    #[test]
+    fn test_push_closed_loan_wraps_around() {
+        // A full shard still fits in a single CPI-created account
+        assert!(LOAN_ARCHIVE_LEN <= 10 * 1024);
+
+        let mut data = vec![0; LOAN_ARCHIVE_LEN];
+        let borrower = Pubkey::new_unique();
+        let record = |final_payment| ClosedLoan {
+            borrower,
+            market: LoanMarket::Usdc,
+            liquidated: false,
+            start_date: 1625097600,
+            closed_at: 1625097600 + 86400,
+            collateral: 600_000_000,
+            final_payment,
+        };
+        let slot = |data: &[u8], i: usize| {
+            let start = LOAN_ARCHIVE_HEADER_LEN + i * CLOSED_LOAN_LEN;
+            ClosedLoan::try_from_slice(&data[start..start + CLOSED_LOAN_LEN]).unwrap()
+        };
+
+        for final_payment in 1..=LOAN_ARCHIVE_CAPACITY as u64 {
+            push_closed_loan(&mut data, &record(final_payment)).unwrap();
+        }
+        assert_eq!(slot(&data, 0), record(1));
+        assert_eq!(slot(&data, LOAN_ARCHIVE_CAPACITY - 1), record(LOAN_ARCHIVE_CAPACITY as u64));
+
+        // Once full, the oldest record is overwritten
+        push_closed_loan(&mut data, &record(1_000)).unwrap();
+        assert_eq!(slot(&data, 0), record(1_000));
+        assert_eq!(slot(&data, 1), record(2));
+        assert_eq!(&data[..LOAN_ARCHIVE_HEADER_LEN], &(LOAN_ARCHIVE_CAPACITY as u64 + 1).to_le_bytes());
+        assert!(loan_archive_shard(&borrower) < LOAN_ARCHIVE_SHARDS);
+    }
+
+    #[test]
+    fn test_sol_loan_collateral_math() {
+        // 1 SOL at $150 and 50% LTV needs twice its value in USDC
+        assert_eq!(required_usdc_collateral(1_000_000_000).unwrap(), 300_000_000);
+        assert_eq!(sol_debt_value(1_000_000_000).unwrap(), 150_000_000);
+        assert_eq!(usdc_collateral_sol_value(150_000_000).unwrap(), 1_000_000_000);
+
+        // All round against the borrower or liquidator
+        assert_eq!(required_usdc_collateral(1).unwrap(), 1);
+        assert_eq!(sol_debt_value(1).unwrap(), 1);
+        assert_eq!(usdc_collateral_sol_value(1).unwrap(), 7);
+    }
+
+    #[test]
+    fn test_error_codes_are_offset() {
+        assert_eq!(ProgramError::from(LoanError::InvalidInstruction), ProgramError::Custom(3000));
+        assert_eq!(ProgramError::from(LoanError::SweepNotReady), ProgramError::Custom(3013));
+    }
+
+    #[test]
+    fn test_holds_credential() {
         let borrower_key = Pubkey::new_unique();
-        let liquidator_key = Pubkey::new_unique();
-        let loan_account_key = Pubkey::new_unique();
-        let usdc_mint_key = Pubkey::new_unique();
-        let liquidator_usdc_account_key = Pubkey::new_unique();
-        let program_usdc_account_key = Pubkey::new_unique();
+        let credential_mint = Pubkey::new_unique();
+        let credential_account_key = Pubkey::new_unique();
+        let token_program_id = spl_token::id();
+
+        let mut credential_lamports = 0;
+        let mut credential_data = vec![0; TokenAccount::LEN];
+        let credential = TokenAccount {
+            mint: credential_mint,
+            owner: borrower_key,
+            amount: 1,
+            state: spl_token::state::AccountState::Frozen,
+            ..TokenAccount::default()
+        };
+        TokenAccount::pack(credential, &mut credential_data).unwrap();
+        let credential_account = create_account_info(&credential_account_key, false, &mut credential_lamports, &mut credential_data, &token_program_id);
 
-        let mut liquidator_lamports = 1000000000; // 10 SOL
-        let mut loan_account_lamports = 100000000; // 1 SOL collateral
-        let mut liquidator_usdc_lamports = 1000000000; // 1000 USDC
-        let mut program_usdc_lamports = 900000000; // 900 USDC
+        assert!(holds_credential(&borrower_key, &credential_account, &credential_mint).unwrap());
+        assert!(!holds_credential(&Pubkey::new_unique(), &credential_account, &credential_mint).unwrap());
+        assert!(!holds_credential(&borrower_key, &credential_account, &Pubkey::new_unique()).unwrap());
+    }
 
-        let mut loan_account_data = LoanAccount {
-            borrower: borrower_key,
-            start_date: 1625097600, // Example start timestamp
-            principal: 100000000, // 100 USDC
-            apy: 500, // 5% APY
-            collateral: 100000000, // 1 SOL
-        }.try_to_vec().unwrap();
-
-        let mut liquidator_usdc_data = vec![0; 165]; // Mocked SPL Token account data
-        let mut program_usdc_data = vec![0; 165]; // Mocked SPL Token account data
-
-        let liquidator_account = create_account_info(&liquidator_key, true, &mut liquidator_lamports, &mut [], &program_id);
-        let loan_account = create_account_info(&loan_account_key, false, &mut loan_account_lamports, &mut loan_account_data, &program_id);
-        let liquidator_usdc_account = create_account_info(&liquidator_usdc_account_key, false, &mut liquidator_usdc_lamports, &mut liquidator_usdc_data, &usdc_mint_key);
-        let program_usdc_account = create_account_info(&program_usdc_account_key, false, &mut program_usdc_lamports, &mut program_usdc_data, &usdc_mint_key);
-
-        let token_program_key = Pubkey::new_unique();
-        let clock_key = Pubkey::new_unique();
-
-        let accounts = vec![
-            liquidator_account,
-            loan_account,
-            liquidator_usdc_account,
-            program_usdc_account,
-            create_account_info(&token_program_key, false, &mut 0, &mut [], &program_id),
-            create_account_info(&clock_key, false, &mut 0, &mut [], &program_id),
-        ];
-
-        let instruction_data = LoanInstruction::LiquidateLoan.try_to_vec().unwrap();
-
-        // Mock Clock sysvar
-        let clock = Clock {
-            slot: 0,
-            epoch_start_timestamp: 0,
-            epoch: 0,
-            leader_schedule_epoch: 0,
-            unix_timestamp: 1625270400, // 2 days later
+    #[test]
+    fn test_check_usdc_account() {
+        let owner_key = Pubkey::new_unique();
+        let token_account_key = Pubkey::new_unique();
+        let token_program_id = spl_token::id();
+
+        let pack = |mint: Pubkey, owner: Pubkey| {
+            let mut data = vec![0; TokenAccount::LEN];
+            let account = TokenAccount {
+                mint,
+                owner,
+                state: spl_token::state::AccountState::Initialized,
+                ..TokenAccount::default()
+            };
+            TokenAccount::pack(account, &mut data).unwrap();
+            data
         };
 
-        // Override the Clock account data
-        accounts[5].data = clock.try_to_vec().unwrap().into();
+        let mut lamports = 0;
+        let mut data = pack(USDC_MINT, owner_key);
+        let usdc_account = create_account_info(&token_account_key, false, &mut lamports, &mut data, &token_program_id);
+        assert_eq!(check_usdc_account(&usdc_account, &owner_key), Ok(()));
+        assert_eq!(check_usdc_account(&usdc_account, &Pubkey::new_unique()), Err(LoanError::InvalidTokenAccount.into()));
+
+        let mut lamports = 0;
+        let mut data = pack(Pubkey::new_unique(), owner_key);
+        let other_mint = create_account_info(&token_account_key, false, &mut lamports, &mut data, &token_program_id);
+        assert_eq!(check_usdc_account(&other_mint, &owner_key), Err(LoanError::InvalidTokenAccount.into()));
+
+        // Look-alike data in an account the token program does not own
+        let fake_owner = Pubkey::new_unique();
+        let mut lamports = 0;
+        let mut data = pack(USDC_MINT, owner_key);
+        let spoofed = create_account_info(&token_account_key, false, &mut lamports, &mut data, &fake_owner);
+        assert_eq!(check_usdc_account(&spoofed, &owner_key), Err(LoanError::InvalidTokenAccount.into()));
+    }
+
+    #[test]
+    fn test_check_max_collateral() {
+        let required = required_collateral(100_000_000).unwrap();
+        assert_eq!(check_max_collateral(required, None), Ok(()));
+        assert_eq!(check_max_collateral(required, Some(required)), Ok(()));
+        assert_eq!(check_max_collateral(required, Some(required - 1)), Err(LoanError::CollateralAboveMaximum.into()));
+    }
+
+    #[test]
+    fn test_check_deadline() {
+        let now = 1625097600;
+        assert_eq!(check_deadline(None, now), Ok(()));
+        assert_eq!(check_deadline(Some(now), now), Ok(()));
+        assert_eq!(check_deadline(Some(now - 1), now), Err(LoanError::DeadlinePassed.into()));
+    }
+
+    #[test]
+    fn test_check_session() {
+        let key = Pubkey::new_unique();
+        let session = Session { session_key: key, expiry: 1_700_000_000 };
+
+        assert_eq!(check_session(&session, &key, 1_700_000_000), Ok(()));
+        assert_eq!(check_session(&session, &key, 1_700_000_001), Err(LoanError::InvalidSessionKey.into()));
+        assert_eq!(check_session(&session, &Pubkey::new_unique(), 1_600_000_000), Err(LoanError::InvalidSessionKey.into()));
+    }
+
+    #[test]
+    fn test_scheduled_installment() {
+        let schedule = RepaymentSchedule {
+            borrower: Pubkey::new_unique(),
+            funding_account: Pubkey::new_unique(),
+            amount: 10_000_000,
+            interval: 86400 * 30,
+            next_due: 1_700_000_000,
+        };
+        assert_eq!(schedule.try_to_vec().unwrap().len(), mem::size_of::<RepaymentSchedule>());
 
-        // Simulate price drop
-        const SOL_PRICE: u64 = 100;  // $100 per SOL (price dropped)
+        assert_eq!(scheduled_installment(&schedule, 50_000_000, 1_699_999_999), Err(LoanError::RepaymentNotDue.into()));
+        assert_eq!(scheduled_installment(&schedule, 50_000_000, 1_700_000_000), Ok(10_000_000));
 
-        // Process the instruction
-        process_instruction(&program_id, &accounts, &instruction_data).unwrap();
+        // The last installment only pays what is left
+        assert_eq!(scheduled_installment(&schedule, 4_000_000, 1_700_000_000), Ok(4_000_000));
+    }
 
-        // Verify the loan was liquidated
-        assert_eq!(liquidator_account.lamports(), 1100000000); // 11 SOL (initial + collateral)
-        assert_eq!(loan_account.lamports(), 0);
-        
-        // In a real test, we would also verify the USDC transfer, but we've mocked the token accounts here
+    // Packed USDC token account owned by `owner`
+    fn usdc_account_data(owner: &Pubkey, amount: u64) -> Vec<u8> {
+        let mut data = vec![0; TokenAccount::LEN];
+        let account = TokenAccount {
+            mint: USDC_MINT,
+            owner: *owner,
+            amount,
+            state: spl_token::state::AccountState::Initialized,
+            ..TokenAccount::default()
+        };
+        TokenAccount::pack(account, &mut data).unwrap();
+        data
     }
 }
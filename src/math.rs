@@ -0,0 +1,74 @@
+// Fixed-point helpers for the lending math.
+//
+// Every ratio is evaluated as a single `a * b / denominator` with a 128-bit
+// intermediate, so nothing is lost to an early division. The only rounding
+// happens once at the end, and the caller picks its direction explicitly:
+// amounts owed to the protocol (collateral required, interest, fees) round
+// up, amounts credited to users (collateral value, principal retired) round
+// down.
+
+// Direction to round the result of a division
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rounding {
+    Down,
+    Up,
+}
+
+// Computes `a * b / denominator`, rounded as requested.
+// Returns None on a zero denominator or if the result does not fit in a u64.
+pub fn mul_div(a: u64, b: u64, denominator: u64, rounding: Rounding) -> Option<u64> {
+    if denominator == 0 {
+        return None;
+    }
+
+    let product = a as u128 * b as u128;
+    let denominator = denominator as u128;
+    let quotient = match rounding {
+        Rounding::Down => product / denominator,
+        Rounding::Up => (product + denominator - 1) / denominator,
+    };
+    u64::try_from(quotient).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exact_division_ignores_rounding() {
+        assert_eq!(mul_div(100, 30, 10, Rounding::Down), Some(300));
+        assert_eq!(mul_div(100, 30, 10, Rounding::Up), Some(300));
+    }
+
+    #[test]
+    fn test_inexact_division_rounds_in_requested_direction() {
+        // 10 * 1 / 3 = 3.33..
+        assert_eq!(mul_div(10, 1, 3, Rounding::Down), Some(3));
+        assert_eq!(mul_div(10, 1, 3, Rounding::Up), Some(4));
+
+        // A single unit of remainder is enough to round up
+        assert_eq!(mul_div(7, 1, 7, Rounding::Up), Some(1));
+        assert_eq!(mul_div(8, 1, 7, Rounding::Up), Some(2));
+    }
+
+    #[test]
+    fn test_sub_unit_results() {
+        // Dust interest rounds up to one base unit owed, never down to zero
+        assert_eq!(mul_div(1, 1, 1_000_000, Rounding::Up), Some(1));
+        assert_eq!(mul_div(1, 1, 1_000_000, Rounding::Down), Some(0));
+        assert_eq!(mul_div(0, 1, 1_000_000, Rounding::Up), Some(0));
+    }
+
+    #[test]
+    fn test_intermediate_product_does_not_overflow() {
+        // u64::MAX * u64::MAX only fits in the 128-bit intermediate
+        assert_eq!(mul_div(u64::MAX, u64::MAX, u64::MAX, Rounding::Down), Some(u64::MAX));
+        assert_eq!(mul_div(u64::MAX, u64::MAX, u64::MAX, Rounding::Up), Some(u64::MAX));
+    }
+
+    #[test]
+    fn test_result_overflow_and_zero_denominator() {
+        assert_eq!(mul_div(u64::MAX, 2, 1, Rounding::Down), None);
+        assert_eq!(mul_div(1, 1, 0, Rounding::Down), None);
+    }
+}
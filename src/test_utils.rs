@@ -0,0 +1,65 @@
+// Unit test helpers shared by the programs' tests: instruction input in the
+// BPF loader's layout and sysvar account data. Each program's tests use a
+// different subset of them.
+#![allow(dead_code)]
+
+use solana_program::{entrypoint, pubkey::Pubkey, rent::Rent};
+
+pub struct TestAccount {
+    pub key: Pubkey,
+    pub is_signer: bool,
+    pub lamports: u64,
+    pub data: Vec<u8>,
+    pub owner: Pubkey,
+}
+
+pub fn test_account(key: Pubkey, is_signer: bool, lamports: u64, data: Vec<u8>, owner: Pubkey) -> TestAccount {
+    TestAccount { key, is_signer, lamports, data, owner }
+}
+
+// Serializes an instruction's input the way the BPF loader does, all
+// accounts writable. AccountInfos deserialized from it behave as they do
+// on-chain, including realloc, which writes to the bytes around the data.
+pub fn serialize_input(program_id: &Pubkey, accounts: &[TestAccount], instruction_data: &[u8]) -> Vec<u64> {
+    let mut input = (accounts.len() as u64).to_le_bytes().to_vec();
+    for account in accounts {
+        input.extend_from_slice(&[entrypoint::NON_DUP_MARKER, account.is_signer as u8, 1, 0]);
+        input.extend_from_slice(&[0; 4]); // original data length, set by deserialize
+        input.extend_from_slice(account.key.as_ref());
+        input.extend_from_slice(account.owner.as_ref());
+        input.extend_from_slice(&account.lamports.to_le_bytes());
+        input.extend_from_slice(&(account.data.len() as u64).to_le_bytes());
+        input.extend_from_slice(&account.data);
+        input.resize(input.len() + entrypoint::MAX_PERMITTED_DATA_INCREASE, 0);
+        input.resize(input.len().next_multiple_of(8), 0);
+        input.extend_from_slice(&0u64.to_le_bytes()); // rent epoch
+    }
+    input.extend_from_slice(&(instruction_data.len() as u64).to_le_bytes());
+    input.extend_from_slice(instruction_data);
+    input.extend_from_slice(program_id.as_ref());
+
+    // Held as u64s so the buffer has the 8-byte alignment deserialize expects
+    input
+        .chunks(8)
+        .map(|chunk| {
+            let mut word = [0; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            u64::from_le_bytes(word)
+        })
+        .collect()
+}
+
+// Bincode layout of the Clock sysvar at `unix_timestamp`
+pub fn clock_sysvar_data(unix_timestamp: i64) -> Vec<u8> {
+    let mut data = vec![0; 32];
+    data.extend_from_slice(&unix_timestamp.to_le_bytes());
+    data
+}
+
+// Bincode layout of the Rent sysvar
+pub fn rent_sysvar_data(rent: &Rent) -> Vec<u8> {
+    let mut data = rent.lamports_per_byte_year.to_le_bytes().to_vec();
+    data.extend_from_slice(&rent.exemption_threshold.to_le_bytes());
+    data.push(rent.burn_percent);
+    data
+}
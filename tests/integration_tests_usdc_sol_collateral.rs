@@ -1,237 +1,482 @@
 use {
+    borsh::{BorshDeserialize, BorshSerialize},
     solana_program::{
-        instruction::{AccountMeta, Instruction},
+        clock::Clock,
+        instruction::{AccountMeta, Instruction, InstructionError},
+        program_option::COption,
+        program_pack::Pack,
         pubkey::Pubkey,
-        system_program,
+        system_program, sysvar,
     },
     solana_program_test::*,
     solana_sdk::{
+        account::Account,
         signature::{Keypair, Signer},
-        transaction::Transaction,
-    },
-    spl_token::{
-        instruction as token_instruction,
-        state::{Account as TokenAccount, Mint},
+        transaction::{Transaction, TransactionError},
     },
+    spl_token::state::{Account as TokenAccount, AccountState, Mint},
 };
 
-use your_crate_name::{
-    processor::process_instruction,
-    state::{LoanAccount, LoanInstruction},
-    id, USDC_MINT, PROGRAM_USDC_ACCOUNT, SOL_PRICE, LTV,
+use radar_lend::lending::{
+    id, initialize_loan_instruction, liquidate_loan_instruction, process_instruction, repay_loan_instruction,
+    required_collateral, BadDebt, LoanAccount, LoanError, LoanInstruction, Stats, BAD_DEBT_LEN, PROGRAM_USDC_ACCOUNT,
+    TREASURY_USDC_ACCOUNT, USDC_MINT,
 };
 
-async fn setup() -> (BanksClient, Keypair, Hash) {
+const DAY: i64 = 24 * 60 * 60;
+const LOAN_AMOUNT: u64 = 1_000_000_000; // 1000 USDC
+// Lamports locked for LOAN_AMOUNT at $150 and 25% LTV (see the collateral golden vectors)
+const LOAN_COLLATERAL: u64 = 26_666_666_667;
+// LoanError codes start at 3000
+const ERROR_CODE_BASE: u32 = 3000;
+// Compute unit budgets per instruction, well under the 200_000 default
+// limit. Raise one only when a change is known to need it.
+const INITIALIZE_LOAN_CU_BUDGET: u64 = 100_000;
+const REPAY_LOAN_CU_BUDGET: u64 = 80_000;
+const LIQUIDATE_LOAN_CU_BUDGET: u64 = 100_000;
+
+// A funded USDC market with a stats account, a borrower holding 30 SOL and
+// 100 USDC, and a liquidator holding 1 SOL and 4000 USDC
+struct Market {
+    context: ProgramTestContext,
+    borrower: Keypair,
+    borrower_usdc: Pubkey,
+    liquidator: Keypair,
+    liquidator_usdc: Pubkey,
+}
+
+async fn setup() -> Market {
     let program_id = id();
-    let mut program_test = ProgramTest::new(
-        "your_program_name",
-        program_id,
-        processor!(process_instruction),
-    );
+    let mut program_test = ProgramTest::new("radar_lend", program_id, processor!(process_instruction));
+
+    let (authority, _) = Pubkey::find_program_address(&[b"authority"], &program_id);
+    let borrower = Keypair::new();
+    let borrower_usdc = Pubkey::new_unique();
+    let liquidator = Keypair::new();
+    let liquidator_usdc = Pubkey::new_unique();
 
-    // Add USDC mint
-    let usdc_mint = Keypair::new();
     program_test.add_packable_account(
         USDC_MINT,
         u32::MAX as u64,
         &Mint {
-            mint_authority: COption::Some(usdc_mint.pubkey()),
-            supply: 1_000_000_000_000, // 1M USDC
+            mint_authority: COption::None,
+            supply: 1_004_100_000_000,
             decimals: 6,
             is_initialized: true,
             freeze_authority: COption::None,
         },
         &spl_token::id(),
     );
+    add_usdc_account(&mut program_test, PROGRAM_USDC_ACCOUNT, authority, 1_000_000_000_000); // 1M USDC
+    add_usdc_account(&mut program_test, TREASURY_USDC_ACCOUNT, Pubkey::new_unique(), 0);
+    add_usdc_account(&mut program_test, borrower_usdc, borrower.pubkey(), 100_000_000);
+    add_usdc_account(&mut program_test, liquidator_usdc, liquidator.pubkey(), 4_000_000_000);
+
+    program_test.add_account(borrower.pubkey(), Account::new(30_000_000_000, 0, &system_program::id()));
+    program_test.add_account(liquidator.pubkey(), Account::new(1_000_000_000, 0, &system_program::id()));
+    program_test.add_account(
+        stats_address(),
+        Account {
+            lamports: 1_000_000_000,
+            data: Stats::default().try_to_vec().unwrap(),
+            owner: program_id,
+            ..Account::default()
+        },
+    );
 
-    // Add program USDC account
+    Market {
+        context: program_test.start_with_context().await,
+        borrower,
+        borrower_usdc,
+        liquidator,
+        liquidator_usdc,
+    }
+}
+
+fn add_usdc_account(program_test: &mut ProgramTest, address: Pubkey, owner: Pubkey, amount: u64) {
     program_test.add_packable_account(
-        PROGRAM_USDC_ACCOUNT,
+        address,
         u32::MAX as u64,
         &TokenAccount {
             mint: USDC_MINT,
-            owner: program_id,
-            amount: 1_000_000_000_000, // 1M USDC
-            state: spl_token::state::AccountState::Initialized,
-            is_native: COption::None,
-            delegated_amount: 0,
-            close_authority: COption::None,
+            owner,
+            amount,
+            state: AccountState::Initialized,
+            ..TokenAccount::default()
         },
         &spl_token::id(),
     );
+}
 
-    program_test.start().await
+fn loan_address(borrower: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[borrower.as_ref(), b"loan"], &id()).0
 }
 
-#[tokio::test]
-async fn test_initialize_loan() {
-    let (mut banks_client, payer, recent_blockhash) = setup().await;
+fn bad_debt_address(borrower: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[borrower.as_ref(), b"bad_debt"], &id()).0
+}
 
-    let borrower = Keypair::new();
-    let loan_amount = 1_000_000_000; // 1000 USDC
-    let apy = 500; // 5% APY
+fn stats_address() -> Pubkey {
+    Pubkey::find_program_address(&[b"stats"], &id()).0
+}
 
-    // Airdrop SOL to borrower
-    let required_collateral = (loan_amount * 100) / (SOL_PRICE * LTV);
-    let airdrop_amount = required_collateral + 1_000_000_000; // Extra for rent and gas
-    let transaction = Transaction::new_signed_with_payer(
-        &[system_instruction::transfer(
-            &payer.pubkey(),
-            &borrower.pubkey(),
-            airdrop_amount,
-        )],
-        Some(&payer.pubkey()),
-        &[&payer],
-        recent_blockhash,
-    );
-    banks_client.process_transaction(transaction).await.unwrap();
+fn initialize_loan(market: &Market, amount: u64, apy: u64, max_collateral: Option<u64>) -> Instruction {
+    initialize_loan_instruction(&id(), &market.borrower.pubkey(), &market.borrower_usdc, None, amount, apy, max_collateral, None)
+}
 
-    // Create borrower's USDC account
-    let borrower_usdc_account = Keypair::new();
-    let transaction = Transaction::new_signed_with_payer(
-        &[
-            system_instruction::create_account(
-                &payer.pubkey(),
-                &borrower_usdc_account.pubkey(),
-                Rent::default().minimum_balance(TokenAccount::LEN),
-                TokenAccount::LEN as u64,
-                &spl_token::id(),
-            ),
-            token_instruction::initialize_account(
-                &spl_token::id(),
-                &borrower_usdc_account.pubkey(),
-                &USDC_MINT,
-                &borrower.pubkey(),
-            )
-            .unwrap(),
+fn repay_loan(market: &Market, amount: u64) -> Instruction {
+    repay_loan_instruction(&id(), &market.borrower.pubkey(), &market.borrower_usdc, amount, None)
+}
+
+fn rollover_loan(market: &Market) -> Instruction {
+    let borrower = market.borrower.pubkey();
+    Instruction::new_with_borsh(
+        id(),
+        &LoanInstruction::RolloverLoan,
+        vec![
+            AccountMeta::new(borrower, true),
+            AccountMeta::new(loan_address(&borrower), false),
+            AccountMeta::new_readonly(sysvar::clock::id(), false),
+            AccountMeta::new(stats_address(), false),
+        ],
+    )
+}
+
+fn liquidate_loan(market: &Market) -> Instruction {
+    liquidate_loan_instruction(&id(), &market.liquidator.pubkey(), &market.liquidator_usdc, &market.borrower.pubkey(), None)
+}
+
+// Repays `amount` of the borrower's bad debt from `payer`'s USDC account,
+// refunding the record's rent to the liquidator that created it
+fn repay_bad_debt(market: &Market, payer: &Keypair, payer_usdc: Pubkey, amount: u64) -> Instruction {
+    Instruction::new_with_borsh(
+        id(),
+        &LoanInstruction::RepayBadDebt { amount },
+        vec![
+            AccountMeta::new(payer.pubkey(), true),
+            AccountMeta::new(bad_debt_address(&market.borrower.pubkey()), false),
+            AccountMeta::new(payer_usdc, false),
+            AccountMeta::new(PROGRAM_USDC_ACCOUNT, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new(stats_address(), false),
+            AccountMeta::new(market.liquidator.pubkey(), false),
         ],
-        Some(&payer.pubkey()),
-        &[&payer, &borrower_usdc_account],
-        recent_blockhash,
+    )
+}
+
+// Sends `instruction` signed by `signer`, with the context payer paying fees
+async fn process(context: &mut ProgramTestContext, instruction: Instruction, signer: &Keypair) -> Result<(), BanksClientError> {
+    let blockhash = context.get_new_latest_blockhash().await.unwrap();
+    let transaction = Transaction::new_signed_with_payer(
+        &[instruction],
+        Some(&context.payer.pubkey()),
+        &[&context.payer, signer],
+        blockhash,
     );
-    banks_client.process_transaction(transaction).await.unwrap();
+    context.banks_client.process_transaction(transaction).await
+}
 
-    // Initialize loan
-    let (loan_account_pubkey, _) = Pubkey::find_program_address(&[borrower.pubkey().as_ref(), b"loan"], &id());
+// Simulates `instruction` signed by `signer` and returns the compute units it
+// consumed. The instruction must succeed; nothing is committed.
+async fn units_consumed(context: &mut ProgramTestContext, instruction: Instruction, signer: &Keypair) -> u64 {
+    let blockhash = context.get_new_latest_blockhash().await.unwrap();
     let transaction = Transaction::new_signed_with_payer(
-        &[Instruction::new_with_borsh(
-            id(),
-            &LoanInstruction::InitializeLoan {
-                amount: loan_amount,
-                apy,
-            },
-            vec![
-                AccountMeta::new(borrower.pubkey(), true),
-                AccountMeta::new(loan_account_pubkey, false),
-                AccountMeta::new_readonly(USDC_MINT, false),
-                AccountMeta::new(borrower_usdc_account.pubkey(), false),
-                AccountMeta::new(PROGRAM_USDC_ACCOUNT, false),
-                AccountMeta::new_readonly(system_program::id(), false),
-                AccountMeta::new_readonly(spl_token::id(), false),
-                AccountMeta::new_readonly(solana_program::sysvar::rent::id(), false),
-            ],
-        )],
-        Some(&borrower.pubkey()),
-        &[&borrower],
-        recent_blockhash,
+        &[instruction],
+        Some(&context.payer.pubkey()),
+        &[&context.payer, signer],
+        blockhash,
     );
-    banks_client.process_transaction(transaction).await.unwrap();
+    let simulation = context.banks_client.simulate_transaction(transaction).await.unwrap();
+    simulation.result.unwrap().unwrap();
+    simulation.simulation_details.unwrap().units_consumed
+}
+
+// Warps to a later slot and sets the clock to exactly `unix_timestamp`
+async fn warp_to_time(context: &mut ProgramTestContext, unix_timestamp: i64) {
+    let slot = context.banks_client.get_root_slot().await.unwrap();
+    context.warp_to_slot(slot + 1_000).unwrap();
 
-    // Verify loan account
-    let loan_account = banks_client.get_account(loan_account_pubkey).await.unwrap().unwrap();
-    let loan_data = LoanAccount::try_from_slice(&loan_account.data).unwrap();
-    assert_eq!(loan_data.borrower, borrower.pubkey());
-    assert_eq!(loan_data.principal, loan_amount);
-    assert_eq!(loan_data.apy, apy);
-    assert_eq!(loan_data.collateral, required_collateral);
+    let mut clock = context.banks_client.get_sysvar::<Clock>().await.unwrap();
+    clock.unix_timestamp = unix_timestamp;
+    context.set_sysvar(&clock);
+}
+
+// Opens a LOAN_AMOUNT loan at `apy` percent, bounded by the collateral the
+// program quotes for it, and returns its start time
+async fn open_loan(market: &mut Market, apy: u64) -> i64 {
+    let max_collateral = required_collateral(LOAN_AMOUNT).unwrap();
+    let instruction = initialize_loan(market, LOAN_AMOUNT, apy, Some(max_collateral));
+    process(&mut market.context, instruction, &market.borrower).await.unwrap();
+    loan_data(market).await.start_date
+}
+
+// Opens a LOAN_AMOUNT loan at 100% APY and liquidates it four years later,
+// when the debt has reached 5000 USDC against collateral worth 4000 USDC
+async fn liquidate_underwater_loan(market: &mut Market) {
+    let start_date = open_loan(market, 100).await;
+    warp_to_time(&mut market.context, start_date + 4 * 365 * DAY).await;
+
+    let instruction = liquidate_loan(market);
+    process(&mut market.context, instruction, &market.liquidator).await.unwrap();
+}
+
+async fn loan_data(market: &mut Market) -> LoanAccount {
+    let address = loan_address(&market.borrower.pubkey());
+    let account = market.context.banks_client.get_account(address).await.unwrap().unwrap();
+    LoanAccount::try_from_slice(&account.data).unwrap()
+}
+
+async fn stats(market: &mut Market) -> Stats {
+    let account = market.context.banks_client.get_account(stats_address()).await.unwrap().unwrap();
+    Stats::try_from_slice(&account.data).unwrap()
+}
+
+async fn usdc_balance(context: &mut ProgramTestContext, address: Pubkey) -> u64 {
+    let account = context.banks_client.get_account(address).await.unwrap().unwrap();
+    TokenAccount::unpack(&account.data).unwrap().amount
+}
 
-    // Verify borrower's USDC balance
-    let borrower_usdc_account_data = banks_client.get_account(borrower_usdc_account.pubkey()).await.unwrap().unwrap();
-    let borrower_usdc_balance = TokenAccount::unpack(&borrower_usdc_account_data.data).unwrap().amount;
-    assert_eq!(borrower_usdc_balance, loan_amount);
+async fn lamports(context: &mut ProgramTestContext, address: Pubkey) -> u64 {
+    context.banks_client.get_account(address).await.unwrap().unwrap().lamports
+}
+
+#[tokio::test]
+async fn test_initialize_loan() {
+    let mut market = setup().await;
+    let borrower = market.borrower.pubkey();
+    let rent = market.context.banks_client.get_rent().await.unwrap();
+
+    open_loan(&mut market, 10).await;
+
+    let loan_data = loan_data(&mut market).await;
+    assert_eq!(loan_data.borrower, borrower);
+    assert_eq!(loan_data.principal, LOAN_AMOUNT);
+    assert_eq!(loan_data.apy, 10);
+    assert_eq!(loan_data.collateral, LOAN_COLLATERAL);
+
+    // The collateral and the loan account's rent come from the borrower
+    let loan_lamports = lamports(&mut market.context, loan_address(&borrower)).await;
+    assert_eq!(loan_lamports, LOAN_COLLATERAL + rent.minimum_balance(std::mem::size_of::<LoanAccount>()));
+    assert_eq!(lamports(&mut market.context, borrower).await, 30_000_000_000 - loan_lamports);
+
+    // The 0.5% origination fee is deducted from the disbursement
+    assert_eq!(usdc_balance(&mut market.context, market.borrower_usdc).await, 100_000_000 + 995_000_000);
+    assert_eq!(usdc_balance(&mut market.context, TREASURY_USDC_ACCOUNT).await, 5_000_000);
+
+    let stats = stats(&mut market).await;
+    assert_eq!(stats.loans_originated, 1);
+    assert_eq!(stats.usdc_borrowed, LOAN_AMOUNT);
+    assert_eq!(stats.sol_collateral_locked, LOAN_COLLATERAL);
+}
+
+#[tokio::test]
+async fn test_initialize_loan_rejects_collateral_above_maximum() {
+    let mut market = setup().await;
+    let borrower = market.borrower.pubkey();
+
+    // A bound one lamport below the quote, as if the price moved after signing
+    let max_collateral = required_collateral(LOAN_AMOUNT).unwrap() - 1;
+    let instruction = initialize_loan(&market, LOAN_AMOUNT, 10, Some(max_collateral));
+    let error = process(&mut market.context, instruction, &market.borrower).await.unwrap_err().unwrap();
+    let code = ERROR_CODE_BASE + LoanError::CollateralAboveMaximum as u32;
+    assert_eq!(error, TransactionError::InstructionError(0, InstructionError::Custom(code)));
+
+    assert!(market.context.banks_client.get_account(loan_address(&borrower)).await.unwrap().is_none());
+    assert_eq!(lamports(&mut market.context, borrower).await, 30_000_000_000);
 }
 
 #[tokio::test]
 async fn test_repay_loan() {
-    // Similar setup to initialize_loan test
-    // ...
+    let mut market = setup().await;
+    let borrower = market.borrower.pubkey();
+    open_loan(&mut market, 10).await;
+    let loan_lamports = lamports(&mut market.context, loan_address(&borrower)).await;
 
-    // Initialize loan
-    // ...
+    // No time has passed, so a partial repayment retires principal only
+    let instruction = repay_loan(&market, 500_000_000);
+    process(&mut market.context, instruction, &market.borrower).await.unwrap();
 
-    // Repay part of the loan
-    let repay_amount = 500_000_000; // 500 USDC
-    let transaction = Transaction::new_signed_with_payer(
-        &[Instruction::new_with_borsh(
-            id(),
-            &LoanInstruction::RepayLoan {
-                amount: repay_amount,
-            },
-            vec![
-                AccountMeta::new(borrower.pubkey(), true),
-                AccountMeta::new(loan_account_pubkey, false),
-                AccountMeta::new(borrower_usdc_account.pubkey(), false),
-                AccountMeta::new(PROGRAM_USDC_ACCOUNT, false),
-                AccountMeta::new_readonly(spl_token::id(), false),
-            ],
-        )],
-        Some(&borrower.pubkey()),
-        &[&borrower],
-        recent_blockhash,
-    );
-    banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(loan_data(&mut market).await.principal, 500_000_000);
+    assert_eq!(usdc_balance(&mut market.context, market.borrower_usdc).await, 1_095_000_000 - 500_000_000);
 
-    // Verify loan account
-    let loan_account = banks_client.get_account(loan_account_pubkey).await.unwrap().unwrap();
-    let loan_data = LoanAccount::try_from_slice(&loan_account.data).unwrap();
-    assert!(loan_data.principal < loan_amount && loan_data.principal > 0);
+    // Repaying the rest returns the collateral and the rent, and the emptied
+    // loan account is removed
+    let lamports_before = lamports(&mut market.context, borrower).await;
+    let instruction = repay_loan(&market, 500_000_000);
+    process(&mut market.context, instruction, &market.borrower).await.unwrap();
 
-    // Verify borrower's USDC balance
-    let borrower_usdc_account_data = banks_client.get_account(borrower_usdc_account.pubkey()).await.unwrap().unwrap();
-    let borrower_usdc_balance = TokenAccount::unpack(&borrower_usdc_account_data.data).unwrap().amount;
-    assert_eq!(borrower_usdc_balance, loan_amount - repay_amount);
+    assert_eq!(lamports(&mut market.context, borrower).await, lamports_before + loan_lamports);
+    assert!(market.context.banks_client.get_account(loan_address(&borrower)).await.unwrap().is_none());
+    assert_eq!(stats(&mut market).await.sol_collateral_locked, 0);
+
+    // The borrower can open a new loan at the same address
+    open_loan(&mut market, 10).await;
+    assert_eq!(loan_data(&mut market).await.principal, LOAN_AMOUNT);
+    assert_eq!(lamports(&mut market.context, loan_address(&borrower)).await, loan_lamports);
+    assert_eq!(stats(&mut market).await.loans_originated, 2);
 }
 
 #[tokio::test]
 async fn test_liquidate_loan() {
-    // Similar setup to initialize_loan test
-    // ...
+    let mut market = setup().await;
+    let borrower = market.borrower.pubkey();
+    let rent = market.context.banks_client.get_rent().await.unwrap();
+    let loan_lamports = LOAN_COLLATERAL + rent.minimum_balance(std::mem::size_of::<LoanAccount>());
 
-    // Initialize loan
-    // ...
+    liquidate_underwater_loan(&mut market).await;
 
-    // Simulate price drop
-    // This would typically be done by updating the SOL_PRICE constant, but for testing purposes,
-    // we can create a situation where the loan becomes underwater
+    // The liquidator pays what the collateral is worth less the 5% bonus,
+    // receives all of it with the loan account's rent, and pays the rent of
+    // the bad debt record
+    assert_eq!(usdc_balance(&mut market.context, market.liquidator_usdc).await, 200_000_000);
+    assert_eq!(
+        lamports(&mut market.context, market.liquidator.pubkey()).await,
+        1_000_000_000 + loan_lamports - rent.minimum_balance(BAD_DEBT_LEN)
+    );
+    assert!(market.context.banks_client.get_account(loan_address(&borrower)).await.unwrap().is_none());
 
-    let liquidator = Keypair::new();
-    // Airdrop SOL and USDC to liquidator
-    // ...
+    // The 3800 USDC paid interest; the rest of the interest and the principal
+    // are left as bad debt
+    let account = market.context.banks_client.get_account(bad_debt_address(&borrower)).await.unwrap().unwrap();
+    let bad_debt = BadDebt::try_from_slice(&account.data).unwrap();
+    assert_eq!(bad_debt.borrower, borrower);
+    assert_eq!(bad_debt.amount, 1_200_000_000);
+    assert_eq!(bad_debt.funder, market.liquidator.pubkey());
 
-    // Liquidate loan
-    let transaction = Transaction::new_signed_with_payer(
-        &[Instruction::new_with_borsh(
-            id(),
-            &LoanInstruction::LiquidateLoan,
-            vec![
-                AccountMeta::new(liquidator.pubkey(), true),
-                AccountMeta::new(loan_account_pubkey, false),
-                AccountMeta::new(liquidator_usdc_account.pubkey(), false),
-                AccountMeta::new(PROGRAM_USDC_ACCOUNT, false),
-                AccountMeta::new_readonly(spl_token::id(), false),
-            ],
-        )],
-        Some(&liquidator.pubkey()),
-        &[&liquidator],
-        recent_blockhash,
+    let stats = stats(&mut market).await;
+    assert_eq!(stats.liquidations, 1);
+    assert_eq!(stats.usdc_interest_paid, 3_800_000_000);
+    assert_eq!(stats.usdc_bad_debt_recorded, 1_200_000_000);
+
+    // The borrower cannot borrow again until the bad debt is repaid
+    let instruction = initialize_loan(&market, 100_000_000, 10, None);
+    let error = process(&mut market.context, instruction, &market.borrower).await.unwrap_err().unwrap();
+    let code = ERROR_CODE_BASE + LoanError::OutstandingBadDebt as u32;
+    assert_eq!(error, TransactionError::InstructionError(0, InstructionError::Custom(code)));
+}
+
+#[tokio::test]
+async fn test_repay_bad_debt() {
+    let mut market = setup().await;
+    let borrower = market.borrower.pubkey();
+    let rent = market.context.banks_client.get_rent().await.unwrap();
+    liquidate_underwater_loan(&mut market).await;
+
+    // A partial payment leaves the record open
+    let instruction = repay_bad_debt(&market, &market.liquidator, market.liquidator_usdc, 200_000_000);
+    process(&mut market.context, instruction, &market.liquidator).await.unwrap();
+    let account = market.context.banks_client.get_account(bad_debt_address(&borrower)).await.unwrap().unwrap();
+    assert_eq!(BadDebt::try_from_slice(&account.data).unwrap().amount, LOAN_AMOUNT);
+
+    // Paying it off closes the record and refunds its rent to the
+    // liquidator that funded it, not to the payer
+    let liquidator_lamports = lamports(&mut market.context, market.liquidator.pubkey()).await;
+    let borrower_lamports = lamports(&mut market.context, borrower).await;
+    let instruction = repay_bad_debt(&market, &market.borrower, market.borrower_usdc, LOAN_AMOUNT);
+    process(&mut market.context, instruction, &market.borrower).await.unwrap();
+
+    assert!(market.context.banks_client.get_account(bad_debt_address(&borrower)).await.unwrap().is_none());
+    assert_eq!(
+        lamports(&mut market.context, market.liquidator.pubkey()).await,
+        liquidator_lamports + rent.minimum_balance(BAD_DEBT_LEN)
     );
-    banks_client.process_transaction(transaction).await.unwrap();
+    assert_eq!(lamports(&mut market.context, borrower).await, borrower_lamports);
+    assert_eq!(usdc_balance(&mut market.context, market.borrower_usdc).await, 1_095_000_000 - LOAN_AMOUNT);
+    assert_eq!(stats(&mut market).await.usdc_bad_debt_repaid, 1_200_000_000);
+
+    // With the bad debt repaid the borrower can borrow again
+    let instruction = initialize_loan(&market, 100_000_000, 10, None);
+    process(&mut market.context, instruction, &market.borrower).await.unwrap();
+    assert_eq!(loan_data(&mut market).await.principal, 100_000_000);
+}
+
+#[tokio::test]
+async fn test_interest_accrual_with_clock_warp() {
+    let mut market = setup().await;
+    let start_date = open_loan(&mut market, 10).await;
+
+    // One day at 10% APY on 1000 USDC, paid off exactly
+    warp_to_time(&mut market.context, start_date + DAY).await;
+    let instruction = repay_loan(&market, 273_973);
+    process(&mut market.context, instruction, &market.borrower).await.unwrap();
+
+    let loan_data_after_a_day = loan_data(&mut market).await;
+    assert_eq!(loan_data_after_a_day.interest_owed, 0);
+    assert_eq!(loan_data_after_a_day.principal, LOAN_AMOUNT);
+    assert_eq!(loan_data_after_a_day.last_accrual, start_date + DAY);
+
+    // 10 USDC at the end of the month pays the other 29 days of interest
+    // first, then principal
+    warp_to_time(&mut market.context, start_date + 30 * DAY).await;
+    let instruction = repay_loan(&market, 10_000_000);
+    process(&mut market.context, instruction, &market.borrower).await.unwrap();
+
+    let loan_data = loan_data(&mut market).await;
+    assert_eq!(loan_data.interest_owed, 0);
+    assert_eq!(loan_data.principal, LOAN_AMOUNT - (10_000_000 - 7_945_206));
+    assert_eq!(loan_data.last_accrual, start_date + 30 * DAY);
+
+    // Accruing daily and then monthly adds up to one 30 day accrual
+    assert_eq!(stats(&mut market).await.usdc_interest_paid, 8_219_179);
+}
+
+#[tokio::test]
+async fn test_rollover_with_clock_warp() {
+    let mut market = setup().await;
+    let start_date = open_loan(&mut market, 10).await;
+
+    // The loan is opened at the maximum LTV, so capitalizing a month of
+    // interest would leave it under-collateralized
+    warp_to_time(&mut market.context, start_date + 30 * DAY).await;
+    let instruction = rollover_loan(&market);
+    let error = process(&mut market.context, instruction, &market.borrower).await.unwrap_err().unwrap();
+    let code = ERROR_CODE_BASE + LoanError::InsufficientCollateral as u32;
+    assert_eq!(error, TransactionError::InstructionError(0, InstructionError::Custom(code)));
+
+    // After 50 USDC pays the month's 8.219179 USDC of interest and some
+    // principal, the next month's interest can be rolled over
+    let instruction = repay_loan(&market, 50_000_000);
+    process(&mut market.context, instruction, &market.borrower).await.unwrap();
+    assert_eq!(loan_data(&mut market).await.principal, 958_219_179);
+
+    warp_to_time(&mut market.context, start_date + 60 * DAY).await;
+    let instruction = rollover_loan(&market);
+    process(&mut market.context, instruction, &market.borrower).await.unwrap();
+
+    let loan_data = loan_data(&mut market).await;
+    assert_eq!(loan_data.principal, 958_219_179 + 7_875_775);
+    assert_eq!(loan_data.interest_owed, 0);
+    assert_eq!(loan_data.start_date, start_date + 60 * DAY);
+    assert_eq!(loan_data.last_accrual, start_date + 60 * DAY);
+
+    // Capitalized interest counts as newly borrowed
+    assert_eq!(stats(&mut market).await.usdc_borrowed, LOAN_AMOUNT + 7_875_775);
+}
+
+#[tokio::test]
+async fn test_compute_unit_budgets() {
+    // Compute is only metered when the program runs as BPF, which ProgramTest
+    // does under `cargo test-bpf`; the native processor consumes none
+    if std::env::var("BPF_OUT_DIR").is_err() && std::env::var("SBF_OUT_DIR").is_err() {
+        return;
+    }
+
+    let mut market = setup().await;
+
+    let max_collateral = required_collateral(LOAN_AMOUNT).unwrap();
+    let instruction = initialize_loan(&market, LOAN_AMOUNT, 100, Some(max_collateral));
+    let units = units_consumed(&mut market.context, instruction, &market.borrower).await;
+    assert!(units <= INITIALIZE_LOAN_CU_BUDGET, "InitializeLoan used {} CU, budget {}", units, INITIALIZE_LOAN_CU_BUDGET);
 
-    // Verify loan account is closed
-    let loan_account = banks_client.get_account(loan_account_pubkey).await.unwrap();
-    assert!(loan_account.is_none());
+    let start_date = open_loan(&mut market, 100).await;
+    let instruction = repay_loan(&market, 500_000_000);
+    let units = units_consumed(&mut market.context, instruction, &market.borrower).await;
+    assert!(units <= REPAY_LOAN_CU_BUDGET, "RepayLoan used {} CU, budget {}", units, REPAY_LOAN_CU_BUDGET);
 
-    // Verify liquidator received collateral
-    let liquidator_account = banks_client.get_account(liquidator.pubkey()).await.unwrap().unwrap();
-    assert!(liquidator_account.lamports > initial_liquidator_balance);
+    // Liquidating an underwater loan creates the bad debt record, its most
+    // expensive path
+    warp_to_time(&mut market.context, start_date + 4 * 365 * DAY).await;
+    let instruction = liquidate_loan(&market);
+    let units = units_consumed(&mut market.context, instruction, &market.liquidator).await;
+    assert!(units <= LIQUIDATE_LOAN_CU_BUDGET, "LiquidateLoan used {} CU, budget {}", units, LIQUIDATE_LOAN_CU_BUDGET);
 }